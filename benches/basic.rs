@@ -6,11 +6,16 @@ use generational_arena::Arena;
 use id_vec::IdVec;
 use rand::{thread_rng, Rng};
 use slab::Slab;
-use slotmap::{DefaultKey, DenseSlotMap, HopSlotMap, SlotMap};
+#[allow(deprecated)]
+use slotmap::HopSlotMap;
+use slotmap::{DefaultKey, DenseSlotMap, SlotMap};
 use stable_vec::{ExternStableVec, InlineStableVec};
 use stash::{Stash, UniqueStash};
-use bvmap::BvMap;
+use bvmap::{AllocPolicy, BvMap, DenseBvMap, GenBvMap, GenKey, ShardedBvMap};
+use std::sync::Mutex;
+use std::thread;
 
+#[allow(deprecated, clippy::redundant_closure)]
 fn inserts(c: &mut Criterion) {
     let size = 10_000;
     let s1: BvMap<usize, usize> = BvMap::new();
@@ -25,6 +30,8 @@ fn inserts(c: &mut Criterion) {
     let s11: IdVec<usize> = IdVec::new();
     let s12: CompactMap<usize> = CompactMap::new();
     let s14: Arena<usize> = Arena::new();
+    let s15: GenBvMap<GenKey, usize> = GenBvMap::new();
+    let s16: DenseBvMap<usize, usize> = DenseBvMap::new();
 
     let mut g = c.benchmark_group("Inserts");
     g.bench_function("BvMap", |b| {
@@ -38,6 +45,28 @@ fn inserts(c: &mut Criterion) {
             BatchSize::SmallInput,
         );
     });
+    g.bench_function("GenBvMap", |b| {
+        b.iter_batched_ref(
+            || s15.clone(),
+            |i| {
+                for a in 0..size {
+                    i.insert(a);
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
+    g.bench_function("DenseBvMap", |b| {
+        b.iter_batched_ref(
+            || s16.clone(),
+            |i| {
+                for a in 0..size {
+                    i.insert(a);
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
     g.bench_function("Stash", |b| {
         b.iter_batched_ref(
             || s2.clone(),
@@ -183,6 +212,7 @@ fn inserts(c: &mut Criterion) {
     });
 }
 
+#[allow(deprecated, clippy::needless_range_loop)]
 fn reinserts(c: &mut Criterion) {
     let size = 10_000;
     let mut s1: BvMap<usize, usize> = BvMap::new();
@@ -203,6 +233,10 @@ fn reinserts(c: &mut Criterion) {
     let mut s12: CompactMap<usize> = CompactMap::new();
     let mut s14: Arena<usize> = Arena::new();
     let mut s14k = Vec::new();
+    let mut s15: GenBvMap<GenKey, usize> = GenBvMap::new();
+    let mut s16: DenseBvMap<usize, usize> = DenseBvMap::new();
+    let mut s15k = Vec::new();
+    let mut s16k = Vec::new();
 
     for a in 0..size {
         s1.insert(a);
@@ -217,6 +251,8 @@ fn reinserts(c: &mut Criterion) {
         s11k.push(s11.insert(a));
         s12.insert(a);
         s14k.push(s14.insert(a));
+        s15k.push(s15.insert(a));
+        s16k.push(s16.insert(a));
     }
     for a in 0..size {
         s1.remove(a);
@@ -231,6 +267,8 @@ fn reinserts(c: &mut Criterion) {
         s11.remove(s11k[a]);
         s12.remove(a);
         s14.remove(s14k[a]);
+        s15.remove(s15k[a]);
+        s16.remove(s16k[a]);
     }
     let mut g = c.benchmark_group("Re-inserts");
     g.bench_function("BvMap", |b| {
@@ -244,6 +282,28 @@ fn reinserts(c: &mut Criterion) {
             BatchSize::SmallInput,
         );
     });
+    g.bench_function("GenBvMap", |b| {
+        b.iter_batched_ref(
+            || s15.clone(),
+            |i| {
+                for a in 0..size {
+                    i.insert(a);
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
+    g.bench_function("DenseBvMap", |b| {
+        b.iter_batched_ref(
+            || s16.clone(),
+            |i| {
+                for a in 0..size {
+                    i.insert(a);
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
     g.bench_function("Stash", |b| {
         b.iter_batched_ref(
             || s2.clone(),
@@ -407,6 +467,7 @@ fn reinserts(c: &mut Criterion) {
         );
     });
 }
+#[allow(deprecated, clippy::needless_range_loop)]
 fn remove(c: &mut Criterion) {
     let size = 10_000;
     let mut s1: BvMap<usize, usize> = BvMap::new();
@@ -427,6 +488,10 @@ fn remove(c: &mut Criterion) {
     let mut s12: CompactMap<usize> = CompactMap::new();
     let mut s14: Arena<usize> = Arena::new();
     let mut s14k = Vec::new();
+    let mut s15: GenBvMap<GenKey, usize> = GenBvMap::new();
+    let mut s16: DenseBvMap<usize, usize> = DenseBvMap::new();
+    let mut s15k = Vec::new();
+    let mut s16k = Vec::new();
 
     for a in 0..size {
         s1.insert(a);
@@ -441,6 +506,8 @@ fn remove(c: &mut Criterion) {
         s11k.push(s11.insert(a));
         s12.insert(a);
         s14k.push(s14.insert(a));
+        s15k.push(s15.insert(a));
+        s16k.push(s16.insert(a));
     }
 
     let mut g = c.benchmark_group("Remove");
@@ -455,6 +522,28 @@ fn remove(c: &mut Criterion) {
             BatchSize::SmallInput,
         );
     });
+    g.bench_function("GenBvMap", |b| {
+        b.iter_batched_ref(
+            || s15.clone(),
+            |i| {
+                for &k in &s15k {
+                    i.remove(k);
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
+    g.bench_function("DenseBvMap", |b| {
+        b.iter_batched_ref(
+            || s16.clone(),
+            |i| {
+                for &k in &s16k {
+                    i.remove(k);
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
     g.bench_function("Stash", |b| {
         b.iter_batched_ref(
             || s2.clone(),
@@ -613,6 +702,7 @@ fn remove(c: &mut Criterion) {
     });
 }
 
+#[allow(deprecated)]
 fn get(c: &mut Criterion) {
     let size = 10_000;
     let mut rng = thread_rng();
@@ -634,6 +724,10 @@ fn get(c: &mut Criterion) {
     let mut s12: CompactMap<usize> = CompactMap::new();
     let mut s14: Arena<usize> = Arena::new();
     let mut s14k = Vec::new();
+    let mut s15: GenBvMap<GenKey, usize> = GenBvMap::new();
+    let mut s16: DenseBvMap<usize, usize> = DenseBvMap::new();
+    let mut s15k = Vec::new();
+    let mut s16k = Vec::new();
 
     for a in 0..size {
         s1.insert(a);
@@ -648,6 +742,8 @@ fn get(c: &mut Criterion) {
         s11k.push(s11.insert(a));
         s12.insert(a);
         s14k.push(s14.insert(a));
+        s15k.push(s15.insert(a));
+        s16k.push(s16.insert(a));
     }
     let mut g = c.benchmark_group("Get");
     g.bench_function("BvMap", |b| {
@@ -661,6 +757,28 @@ fn get(c: &mut Criterion) {
             BatchSize::SmallInput,
         );
     });
+    g.bench_function("GenBvMap", |b| {
+        b.iter_batched_ref(
+            || s15.clone(),
+            |i| {
+                for _ in 0..size {
+                    black_box(i.get(s15k[rng.gen_range(0, size)]));
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
+    g.bench_function("DenseBvMap", |b| {
+        b.iter_batched_ref(
+            || s16.clone(),
+            |i| {
+                for _ in 0..size {
+                    black_box(i.get(s16k[rng.gen_range(0, size)]));
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
     g.bench_function("Stash", |b| {
         b.iter_batched_ref(
             || s2.clone(),
@@ -820,6 +938,7 @@ fn get(c: &mut Criterion) {
     });
 }
 
+#[allow(deprecated)]
 fn iter(c: &mut Criterion) {
     let size = 10_000;
     let mut rng = thread_rng();
@@ -847,6 +966,10 @@ fn iter(c: &mut Criterion) {
     let mut s12k = Vec::new();
     let mut s14: Arena<usize> = Arena::new();
     let mut s14k = Vec::new();
+    let mut s15: GenBvMap<GenKey, usize> = GenBvMap::new();
+    let mut s16: DenseBvMap<usize, usize> = DenseBvMap::new();
+    let mut s15k = Vec::new();
+    let mut s16k = Vec::new();
 
     for a in 0..size {
         s1k.push(s1.insert(a));
@@ -861,6 +984,8 @@ fn iter(c: &mut Criterion) {
         s11k.push(s11.insert(a));
         s12k.push(s12.insert(a));
         s14k.push(s14.insert(a));
+        s15k.push(s15.insert(a));
+        s16k.push(s16.insert(a));
     }
 
     let mut g = c.benchmark_group("Iterate");
@@ -875,6 +1000,28 @@ fn iter(c: &mut Criterion) {
             BatchSize::SmallInput,
         )
     });
+    g.bench_function("GenBvMap", |b| {
+        b.iter_batched_ref(
+            || s15.clone(),
+            |i| {
+                for a in i.iter() {
+                    black_box(a);
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    g.bench_function("DenseBvMap", |b| {
+        b.iter_batched_ref(
+            || s16.clone(),
+            |i| {
+                for a in i.iter() {
+                    black_box(a);
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
     g.bench_function("Stash", |b| {
         b.iter_batched_ref(
             || s2.clone(),
@@ -1060,6 +1207,10 @@ fn iter(c: &mut Criterion) {
         s12k.swap_remove(k);
         s14.remove(s14k[k]);
         s14k.swap_remove(k);
+        s15.remove(s15k[k]);
+        s16.remove(s16k[k]);
+        s15k.swap_remove(k);
+        s16k.swap_remove(k);
     }
 
     let mut g = c.benchmark_group("Iterate half-full");
@@ -1074,6 +1225,28 @@ fn iter(c: &mut Criterion) {
             BatchSize::SmallInput,
         )
     });
+    g.bench_function("GenBvMap", |b| {
+        b.iter_batched_ref(
+            || s15.clone(),
+            |i| {
+                for a in i.iter() {
+                    black_box(a);
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    g.bench_function("DenseBvMap", |b| {
+        b.iter_batched_ref(
+            || s16.clone(),
+            |i| {
+                for a in i.iter() {
+                    black_box(a);
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
     g.bench_function("Stash", |b| {
         b.iter_batched_ref(
             || s2.clone(),
@@ -1243,5 +1416,128 @@ fn iter(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, inserts, reinserts, remove, get, iter);
+fn policies(c: &mut Criterion) {
+    let size = 10_000;
+    let mut rng = thread_rng();
+    let mut s1: BvMap<usize, usize> = BvMap::with_policy(AllocPolicy::Lifo);
+    let mut s1k = Vec::new();
+    let mut s2: BvMap<usize, usize> = BvMap::with_policy(AllocPolicy::LowestFirst);
+    let mut s2k = Vec::new();
+
+    for a in 0..size {
+        s1k.push(s1.insert(a));
+        s2k.push(s2.insert(a));
+    }
+    for subset in ((size / 2)..size).rev() {
+        let k = rng.gen_range(0, subset);
+        s1.remove(s1k[k]);
+        s1k.swap_remove(k);
+        s2.remove(s2k[k]);
+        s2k.swap_remove(k);
+    }
+
+    let mut g = c.benchmark_group("Allocation policy: re-insert half");
+    g.bench_function("Lifo", |b| {
+        b.iter_batched_ref(
+            || s1.clone(),
+            |i| {
+                for a in 0..(size / 2) {
+                    i.insert(a);
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
+    g.bench_function("LowestFirst", |b| {
+        b.iter_batched_ref(
+            || s2.clone(),
+            |i| {
+                for a in 0..(size / 2) {
+                    i.insert(a);
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
+    g.finish();
+
+    for a in 0..(size / 4) {
+        s1.insert(a);
+        s2.insert(a);
+    }
+
+    let mut g = c.benchmark_group("Allocation policy: iterate after churn");
+    g.bench_function("Lifo", |b| {
+        b.iter_batched_ref(
+            || s1.clone(),
+            |i| {
+                for a in i.iter() {
+                    black_box(a);
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    g.bench_function("LowestFirst", |b| {
+        b.iter_batched_ref(
+            || s2.clone(),
+            |i| {
+                for a in i.iter() {
+                    black_box(a);
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn threads(c: &mut Criterion) {
+    let size = 10_000;
+    let threads = 4;
+
+    // Each thread inserts its share of values, reads them back and removes
+    // them again.
+    let mut g = c.benchmark_group("Threads: insert, get and remove");
+    g.bench_function("Mutex<BvMap>", |b| {
+        let map: Mutex<BvMap<usize, usize>> = Mutex::new(BvMap::new());
+        b.iter(|| {
+            thread::scope(|scope| {
+                for _ in 0..threads {
+                    scope.spawn(|| {
+                        let keys: Vec<usize> = (0..size / threads)
+                            .map(|a| map.lock().unwrap().insert(a))
+                            .collect();
+                        for &k in &keys {
+                            black_box(map.lock().unwrap()[k]);
+                        }
+                        for k in keys {
+                            map.lock().unwrap().remove(k);
+                        }
+                    });
+                }
+            })
+        });
+    });
+    g.bench_function("ShardedBvMap", |b| {
+        let map: ShardedBvMap<usize, usize> = ShardedBvMap::with_shards(threads);
+        b.iter(|| {
+            thread::scope(|scope| {
+                for _ in 0..threads {
+                    scope.spawn(|| {
+                        let keys: Vec<usize> = (0..size / threads).map(|a| map.insert(a)).collect();
+                        for &k in &keys {
+                            black_box(map.get(k));
+                        }
+                        for k in keys {
+                            map.remove(k);
+                        }
+                    });
+                }
+            })
+        });
+    });
+    g.finish();
+}
+
+criterion_group!(benches, inserts, reinserts, remove, get, iter, policies, threads);
 criterion_main!(benches);
@@ -0,0 +1,4 @@
+fn main() {
+    // Declared here rather than in a `[lints]` table, which needs Cargo 1.74.
+    println!("cargo:rustc-check-cfg=cfg(loom)");
+}
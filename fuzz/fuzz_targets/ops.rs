@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../tests/ops/mod.rs"]
+mod ops;
+
+fuzz_target!(|data: &[u8]| {
+    ops::run(&ops::Op::decode(data));
+});
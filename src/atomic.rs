@@ -0,0 +1,237 @@
+use crate::bitset::BITS;
+use crate::{InsertError, Key};
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+
+#[cfg(loom)]
+use loom::cell::UnsafeCell;
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+#[cfg(not(loom))]
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+// Same interface as loom's cell, so that loom can check every value access.
+#[cfg(not(loom))]
+struct UnsafeCell<T>(std::cell::UnsafeCell<T>);
+
+#[cfg(not(loom))]
+impl<T> UnsafeCell<T> {
+    fn new(value: T) -> UnsafeCell<T> {
+        UnsafeCell(std::cell::UnsafeCell::new(value))
+    }
+
+    fn with<R>(&self, f: impl FnOnce(*const T) -> R) -> R {
+        f(self.0.get())
+    }
+
+    fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+        f(self.0.get())
+    }
+}
+
+// Marks the end of the free list.
+const NIL: u32 = u32::MAX;
+
+fn pack(tag: u32, index: u32) -> u64 {
+    (tag as u64) << 32 | index as u64
+}
+
+fn unpack(head: u64) -> (u32, u32) {
+    ((head >> 32) as u32, head as u32)
+}
+
+/// A fixed capacity map that threads can insert into and remove from at the
+/// same time without locks.
+///
+/// An insert takes a slot off a lock-free free list, or claims a slot that
+/// was never used, writes the value and then publishes it by setting its bit
+/// in the occupancy bitmap with `fetch_or`. A remove claims the value by
+/// clearing the bit with `fetch_and`, so that only one thread can get it, and
+/// pushes the slot back onto the free list.
+///
+/// Looking at values needs `&mut self`, because with `&self` another thread
+/// could remove a value while it is being read.
+pub struct AtomicBvMap<K, V> {
+    // Head of the free list: a slot index in the low half and a tag in the
+    // high half. The tag changes on every update, so that a compare-exchange
+    // fails if the head was popped and pushed back in between.
+    head: AtomicU64,
+    // Slots below this have been used at least once. The ones above are not
+    // on the free list.
+    high_water: AtomicUsize,
+    len: AtomicUsize,
+    bits: Box<[AtomicU64]>,
+    links: Box<[AtomicU32]>,
+    values: Box<[UnsafeCell<MaybeUninit<V>>]>,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+// Values are moved in and out through `&self` on any thread, but never shared.
+unsafe impl<K, V: Send> Sync for AtomicBvMap<K, V> {}
+
+impl<K: Key, V> AtomicBvMap<K, V> {
+    /// Creates a map with room for `capacity` values. It never grows.
+    ///
+    /// Panics if `capacity` does not fit in a `u32`, or if the key type cannot
+    /// represent the highest index.
+    pub fn with_capacity(capacity: usize) -> AtomicBvMap<K, V> {
+        assert!(
+            capacity <= NIL as usize && capacity <= K::MAX_INDEX.saturating_add(1),
+            "AtomicBvMap capacity too large"
+        );
+        AtomicBvMap {
+            head: AtomicU64::new(pack(0, NIL)),
+            high_water: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            bits: (0..capacity.div_ceil(BITS))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            links: (0..capacity).map(|_| AtomicU32::new(NIL)).collect(),
+            values: (0..capacity)
+                .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+                .collect(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.values.len()
+    }
+
+    /// The number of values. Inserts and removes that are still running may
+    /// or may not be counted.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Panics if the map is full.
+    pub fn insert(&self, v: V) -> K {
+        match self.try_insert(v) {
+            Ok(key) => key,
+            Err((e, _)) => panic!("AtomicBvMap insert failed: {}", e),
+        }
+    }
+
+    /// Inserts `v`, or returns `InsertError::CapacityLimit` together with `v`
+    /// if the map is full.
+    pub fn try_insert(&self, v: V) -> Result<K, (InsertError, V)> {
+        let index = match self.pop_free().or_else(|| self.claim_unused()) {
+            Some(index) => index,
+            None => return Err((InsertError::CapacityLimit, v)),
+        };
+        // Nobody else can reach the slot until its bit is set.
+        self.values[index].with_mut(|slot| unsafe { (*slot).write(v) });
+        self.len.fetch_add(1, Ordering::Relaxed);
+        self.bits[index / BITS].fetch_or(1 << (index % BITS), Ordering::Release);
+        Ok(K::from_index(index))
+    }
+
+    pub fn remove(&self, k: K) -> Option<V> {
+        let index = k.index();
+        let mask = 1 << (index % BITS);
+        let word = self.bits.get(index / BITS)?;
+        if word.fetch_and(!mask, Ordering::Acquire) & mask == 0 {
+            return None;
+        }
+        // Clearing the bit made the value ours.
+        let v = self.values[index].with(|slot| unsafe { (*slot).assume_init_read() });
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        self.push_free(index);
+        Some(v)
+    }
+
+    pub fn contains_key(&self, k: K) -> bool {
+        let index = k.index();
+        match self.bits.get(index / BITS) {
+            Some(word) => word.load(Ordering::Acquire) & (1 << (index % BITS)) != 0,
+            None => false,
+        }
+    }
+
+    pub fn get(&mut self, k: K) -> Option<&V> {
+        if self.contains_key(k) {
+            let value = self.values[k.index()].with(|slot| slot as *const V);
+            Some(unsafe { &*value })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, k: K) -> Option<&mut V> {
+        if self.contains_key(k) {
+            let value = self.values[k.index()].with_mut(|slot| slot as *mut V);
+            Some(unsafe { &mut *value })
+        } else {
+            None
+        }
+    }
+
+    fn pop_free(&self) -> Option<usize> {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            let (tag, index) = unpack(head);
+            if index == NIL {
+                return None;
+            }
+            // The slot may be popped by another thread before this load, in
+            // which case the link is stale but the tag makes the exchange fail.
+            let next = self.links[index as usize].load(Ordering::Relaxed);
+            match self.head.compare_exchange_weak(
+                head,
+                pack(tag.wrapping_add(1), next),
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(index as usize),
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn push_free(&self, index: usize) {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            let (tag, next) = unpack(head);
+            self.links[index].store(next, Ordering::Relaxed);
+            match self.head.compare_exchange_weak(
+                head,
+                pack(tag.wrapping_add(1), index as u32),
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn claim_unused(&self) -> Option<usize> {
+        let capacity = self.capacity();
+        self.high_water
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                if used < capacity {
+                    Some(used + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+    }
+}
+
+impl<K, V> Drop for AtomicBvMap<K, V> {
+    fn drop(&mut self) {
+        for (word_index, word) in self.bits.iter().enumerate() {
+            let mut word = word.load(Ordering::Acquire);
+            while word != 0 {
+                let index = word_index * BITS + word.trailing_zeros() as usize;
+                word &= word - 1;
+                self.values[index].with_mut(|slot| unsafe { (*slot).assume_init_drop() });
+            }
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::TryReserveError;
+use std::ops::Index;
+
+pub(crate) const BITS: usize = 64;
+
+/// Growable bitset stored as 64-bit words, so that runs of clear bits can be
+/// skipped a word at a time.
+#[derive(Clone, Default)]
+pub(crate) struct BitSet {
+    words: Vec<u64>,
+    len: usize,
+}
+
+fn words_for(bits: usize) -> usize {
+    bits.div_ceil(BITS)
+}
+
+impl BitSet {
+    pub fn new() -> BitSet {
+        BitSet {
+            words: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn with_capacity(bits: usize) -> BitSet {
+        BitSet {
+            words: Vec::with_capacity(words_for(bits)),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, index: usize) -> Option<bool> {
+        if index < self.len {
+            Some(self.words[index / BITS] & (1 << (index % BITS)) != 0)
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, index: usize, value: bool) {
+        assert!(index < self.len, "bit index out of range");
+        let word = &mut self.words[index / BITS];
+        if value {
+            *word |= 1 << (index % BITS);
+        } else {
+            *word &= !(1 << (index % BITS));
+        }
+    }
+
+    pub fn push(&mut self, value: bool) {
+        if self.len == self.words.len() * BITS {
+            self.words.push(0);
+        }
+        self.len += 1;
+        self.set(self.len - 1, value);
+    }
+
+    /// Returns the index of the first clear bit at or after `start`.
+    pub fn first_zero_from(&self, start: usize) -> Option<usize> {
+        let mut word_index = start / BITS;
+        let mut mask = !0 << (start % BITS);
+        while let Some(word) = self.words.get(word_index) {
+            let zeros = !word & mask;
+            if zeros != 0 {
+                let index = word_index * BITS + zeros.trailing_zeros() as usize;
+                return if index < self.len { Some(index) } else { None };
+            }
+            word_index += 1;
+            mask = !0;
+        }
+        None
+    }
+
+    /// Returns the index of the first set bit at or after `start`.
+    pub fn first_one_from(&self, start: usize) -> Option<usize> {
+        let mut word_index = start / BITS;
+        let mut mask = !0 << (start % BITS);
+        while let Some(word) = self.words.get(word_index) {
+            let ones = word & mask;
+            if ones != 0 {
+                return Some(word_index * BITS + ones.trailing_zeros() as usize);
+            }
+            word_index += 1;
+            mask = !0;
+        }
+        None
+    }
+
+    /// Clears every bit and sets the length to zero, keeping the allocation.
+    pub fn clear(&mut self) {
+        self.words.clear();
+        self.len = 0;
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len {
+            self.words.truncate(words_for(len));
+            let tail = len % BITS;
+            if tail > 0 {
+                *self.words.last_mut().unwrap() &= (1 << tail) - 1;
+            }
+            self.len = len;
+        }
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        let needed = words_for(self.len + additional);
+        if needed > self.words.len() {
+            self.words.reserve(needed - self.words.len());
+        }
+    }
+
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        let needed = words_for(self.len.saturating_add(additional));
+        if needed > self.words.len() {
+            self.words.try_reserve(needed - self.words.len())?;
+        }
+        Ok(())
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.words.shrink_to_fit();
+    }
+
+    pub fn ones(&self) -> Ones<'_> {
+        ones_in(&self.words)
+    }
+
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+}
+
+/// Iterates over the set bits of `words`, numbered from the first word.
+pub(crate) fn ones_in(words: &[u64]) -> Ones<'_> {
+    Ones {
+        words,
+        cursor: OnesCursor::new(),
+    }
+}
+
+impl Index<usize> for BitSet {
+    type Output = bool;
+
+    fn index(&self, index: usize) -> &bool {
+        if self.get(index).expect("bit index out of range") {
+            &true
+        } else {
+            &false
+        }
+    }
+}
+
+/// Position of an iteration over the set bits of a word slice. Kept separate
+/// from the slice so that owning iterators can hold the words themselves.
+#[derive(Clone)]
+struct OnesCursor {
+    next_word: usize,
+    word: u64,
+    base: usize,
+}
+
+impl OnesCursor {
+    fn new() -> OnesCursor {
+        OnesCursor {
+            next_word: 0,
+            word: 0,
+            base: 0,
+        }
+    }
+
+    fn next(&mut self, words: &[u64]) -> Option<usize> {
+        while self.word == 0 {
+            self.word = *words.get(self.next_word)?;
+            self.base = self.next_word * BITS;
+            self.next_word += 1;
+        }
+        let bit = self.word.trailing_zeros() as usize;
+        self.word &= self.word - 1;
+        Some(self.base + bit)
+    }
+}
+
+/// Iterator over the indices of the set bits, in increasing order.
+#[derive(Clone)]
+pub(crate) struct Ones<'a> {
+    words: &'a [u64],
+    cursor: OnesCursor,
+}
+
+impl<'a> Iterator for Ones<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        self.cursor.next(self.words)
+    }
+}
+
+pub(crate) struct IntoOnes {
+    words: Vec<u64>,
+    cursor: OnesCursor,
+}
+
+impl Iterator for IntoOnes {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        self.cursor.next(&self.words)
+    }
+}
+
+impl IntoIterator for BitSet {
+    type Item = usize;
+    type IntoIter = IntoOnes;
+
+    fn into_iter(self) -> IntoOnes {
+        IntoOnes {
+            words: self.words,
+            cursor: OnesCursor::new(),
+        }
+    }
+}
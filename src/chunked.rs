@@ -0,0 +1,277 @@
+use crate::bitset::BITS;
+use crate::{Key, Slot};
+use std::marker::PhantomData;
+use std::mem::{forget, needs_drop, replace, ManuallyDrop};
+use std::ops::Index;
+use std::pin::Pin;
+use std::slice;
+
+// A block of slots that is allocated once and never moves. Only the boxes are
+// moved when `chunks` grows.
+struct Chunk<V> {
+    occupied: u64,
+    slots: Box<[Slot<V>]>,
+}
+
+impl<V> Chunk<V> {
+    fn new() -> Chunk<V> {
+        Chunk {
+            occupied: 0,
+            slots: (0..BITS).map(|_| Slot { next_free: 0 }).collect(),
+        }
+    }
+}
+
+/// A `BvMap` whose slots live in fixed blocks of 64, each with its own
+/// occupancy word. Growing allocates a new block instead of reallocating, so
+/// values stay at the same address for as long as they are in the map and can
+/// be pinned.
+///
+/// Only values that are `Unpin` can be moved out with `get_mut` or `remove`.
+/// Other values are dropped in place by `delete`.
+pub struct ChunkedBvMap<K, V> {
+    // Head of the free list. Equal to `slots` when the list is empty.
+    next_free: usize,
+    len: usize,
+    // Number of slots that have been used at least once. Slots past this in
+    // the last chunk are not on the free list yet.
+    slots: usize,
+    chunks: Vec<Chunk<V>>,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<K: Key, V> ChunkedBvMap<K, V> {
+    pub fn new() -> ChunkedBvMap<K, V> {
+        ChunkedBvMap {
+            next_free: 0,
+            len: 0,
+            slots: 0,
+            chunks: Vec::new(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.chunks.len() * BITS
+    }
+
+    /// Like `insert_pin`, for callers that do not need the pinned reference.
+    pub fn insert(&mut self, v: V) -> K {
+        self.insert_pin(v).0
+    }
+
+    /// Inserts `v` and returns its key together with a pinned reference to it.
+    pub fn insert_pin(&mut self, v: V) -> (K, Pin<&mut V>) {
+        let index = self.next_free;
+        let key = K::try_from_index(index).expect("ChunkedBvMap key space exhausted");
+        let chunk_index = index / BITS;
+        let bit = index % BITS;
+        if index == self.slots {
+            if chunk_index == self.chunks.len() {
+                self.chunks.push(Chunk::new());
+            }
+            self.slots += 1;
+            self.next_free = self.slots;
+        } else {
+            self.next_free = unsafe { self.chunks[chunk_index].slots[bit].next_free };
+        }
+        let chunk = &mut self.chunks[chunk_index];
+        chunk.slots[bit] = Slot {
+            value: ManuallyDrop::new(v),
+        };
+        chunk.occupied |= 1 << bit;
+        self.len += 1;
+        let value = unsafe { &mut *chunk.slots[bit].value };
+        (key, unsafe { Pin::new_unchecked(value) })
+    }
+
+    fn occupied(&self, index: usize) -> bool {
+        match self.chunks.get(index / BITS) {
+            Some(chunk) => chunk.occupied & (1 << (index % BITS)) != 0,
+            None => false,
+        }
+    }
+
+    pub fn contains_key(&self, k: K) -> bool {
+        self.occupied(k.index())
+    }
+
+    pub fn get(&self, k: K) -> Option<&V> {
+        let index = k.index();
+        if self.occupied(index) {
+            Some(unsafe { &*self.chunks[index / BITS].slots[index % BITS].value })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_pin_mut(&mut self, k: K) -> Option<Pin<&mut V>> {
+        let index = k.index();
+        if self.occupied(index) {
+            let value = unsafe { &mut *self.chunks[index / BITS].slots[index % BITS].value };
+            Some(unsafe { Pin::new_unchecked(value) })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, k: K) -> Option<&mut V>
+    where
+        V: Unpin,
+    {
+        self.get_pin_mut(k).map(Pin::into_inner)
+    }
+
+    // Clears the occupancy bit and links the slot into the free list, handing
+    // back the slot so the caller decides what happens to the value.
+    fn vacate(&mut self, index: usize) -> Option<Slot<V>> {
+        if !self.occupied(index) {
+            return None;
+        }
+        let chunk = &mut self.chunks[index / BITS];
+        let bit = index % BITS;
+        chunk.occupied &= !(1 << bit);
+        self.len -= 1;
+        let slot = replace(
+            &mut chunk.slots[bit],
+            Slot {
+                next_free: self.next_free,
+            },
+        );
+        self.next_free = index;
+        Some(slot)
+    }
+
+    pub fn remove(&mut self, k: K) -> Option<V>
+    where
+        V: Unpin,
+    {
+        let slot = self.vacate(k.index())?;
+        Some(ManuallyDrop::into_inner(unsafe { slot.value }))
+    }
+
+    /// Drops the value stored at `k` without moving it. Returns whether there
+    /// was one.
+    pub fn delete(&mut self, k: K) -> bool {
+        let index = k.index();
+        if !self.occupied(index) {
+            return false;
+        }
+        // The slot is marked vacant before the drop, so a panicking destructor
+        // cannot lead to a second drop. In that case the slot is not linked
+        // into the free list below and is never reused.
+        let chunk = &mut self.chunks[index / BITS];
+        let bit = index % BITS;
+        chunk.occupied &= !(1 << bit);
+        self.len -= 1;
+        unsafe { ManuallyDrop::drop(&mut chunk.slots[bit].value) };
+        chunk.slots[bit].next_free = self.next_free;
+        self.next_free = index;
+        true
+    }
+
+    pub fn iter(&self) -> ChunkedIter<'_, K, V> {
+        ChunkedIter {
+            chunks: self.chunks.iter(),
+            chunk: None,
+            word: 0,
+            base: 0,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.iter().map(|(k, _)| k)
+    }
+}
+
+impl<K: Key, V> Default for ChunkedBvMap<K, V> {
+    fn default() -> Self {
+        ChunkedBvMap::new()
+    }
+}
+
+impl<K: Key, V> Index<K> for ChunkedBvMap<K, V> {
+    type Output = V;
+
+    fn index(&self, k: K) -> &V {
+        self.get(k).expect("invalid ChunkedBvMap key")
+    }
+}
+
+impl<'a, K: Key, V> IntoIterator for &'a ChunkedBvMap<K, V> {
+    type Item = (K, &'a V);
+    type IntoIter = ChunkedIter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// Clears each bit before dropping its value, so that a second call after a
+// panicking destructor carries on with the values that are left.
+fn drop_occupied<V>(chunks: &mut [Chunk<V>]) {
+    for chunk in chunks {
+        while chunk.occupied != 0 {
+            let bit = chunk.occupied.trailing_zeros() as usize;
+            chunk.occupied &= chunk.occupied - 1;
+            unsafe { ManuallyDrop::drop(&mut chunk.slots[bit].value) };
+        }
+    }
+}
+
+// Drops the remaining values while unwinding, like `drop_in_place` on a slice.
+// Pinned values must be dropped before the chunks are freed.
+struct DropGuard<'a, V>(&'a mut [Chunk<V>]);
+
+impl<'a, V> Drop for DropGuard<'a, V> {
+    fn drop(&mut self) {
+        drop_occupied(self.0);
+    }
+}
+
+impl<K, V> Drop for ChunkedBvMap<K, V> {
+    fn drop(&mut self) {
+        if needs_drop::<V>() {
+            let guard = DropGuard(&mut self.chunks);
+            drop_occupied(&mut *guard.0);
+            forget(guard);
+        }
+    }
+}
+
+pub struct ChunkedIter<'a, K, V> {
+    chunks: slice::Iter<'a, Chunk<V>>,
+    chunk: Option<&'a Chunk<V>>,
+    word: u64,
+    // Index of the first slot of the chunk after `chunk`.
+    base: usize,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<'a, K: Key, V> Iterator for ChunkedIter<'a, K, V> {
+    type Item = (K, &'a V);
+
+    fn next(&mut self) -> Option<(K, &'a V)> {
+        while self.word == 0 {
+            let chunk = self.chunks.next()?;
+            self.chunk = Some(chunk);
+            self.word = chunk.occupied;
+            self.base += BITS;
+        }
+        let bit = self.word.trailing_zeros() as usize;
+        self.word &= self.word - 1;
+        let slot = &self.chunk?.slots[bit];
+        Some((K::from_index(self.base - BITS + bit), unsafe {
+            &*slot.value
+        }))
+    }
+}
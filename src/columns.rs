@@ -0,0 +1,420 @@
+use crate::bitset::{ones_in, BitSet, Ones};
+use crate::Key;
+use std::marker::PhantomData;
+use std::mem::{needs_drop, MaybeUninit};
+use std::ops::{Index, IndexMut};
+use std::ptr;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Row type of a `BvColumns`. Implemented for tuples of up to six types, each
+/// of which becomes a column.
+pub trait Columns: sealed::Sealed + Sized {
+    #[doc(hidden)]
+    type Vecs: Default;
+
+    #[doc(hidden)]
+    fn push_vacant(vecs: &mut Self::Vecs);
+
+    #[doc(hidden)]
+    fn write(vecs: &mut Self::Vecs, index: usize, row: Self);
+
+    #[doc(hidden)]
+    unsafe fn read(vecs: &mut Self::Vecs, index: usize) -> Self;
+
+    #[doc(hidden)]
+    unsafe fn drop_at(vecs: &mut Self::Vecs, index: usize);
+}
+
+/// A `BvMap` whose values are split into columns, so that each component of
+/// a row can be iterated on its own without touching the others.
+///
+/// All columns share one set of occupancy bits and one free list. A chosen
+/// set of columns can be walked together with `Join::join`.
+///
+/// ```
+/// use bvmap::{BvColumns, Join};
+///
+/// let mut map: BvColumns<usize, (f32, f32, &str)> = BvColumns::new();
+/// let k = map.insert((1.0, 0.5, "ball"));
+/// let (position, velocity, _) = map.columns_mut();
+/// for (_, (p, v)) in (position, velocity).join() {
+///     *p += *v;
+/// }
+/// assert_eq!(map.get(k), Some((&1.5, &0.5, &"ball")));
+/// ```
+pub struct BvColumns<K, C: Columns> {
+    len: usize,
+    // Vacant rows, the most recently freed last.
+    free: Vec<usize>,
+    bitvec: BitSet,
+    columns: C::Vecs,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<K: Key, C: Columns> BvColumns<K, C> {
+    pub fn new() -> BvColumns<K, C> {
+        BvColumns {
+            len: 0,
+            free: Vec::new(),
+            bitvec: BitSet::new(),
+            columns: C::Vecs::default(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reuses the row vacated most recently, if there is one.
+    pub fn insert(&mut self, row: C) -> K {
+        let index = self.free.last().copied().unwrap_or(self.bitvec.len());
+        let key = K::try_from_index(index).expect("BvColumns key space exhausted");
+        if self.free.pop().is_none() {
+            C::push_vacant(&mut self.columns);
+            self.bitvec.push(false);
+        }
+        C::write(&mut self.columns, index, row);
+        self.bitvec.set(index, true);
+        self.len += 1;
+        key
+    }
+
+    pub fn contains_key(&self, k: K) -> bool {
+        self.bitvec.get(k.index()) == Some(true)
+    }
+
+    pub fn remove(&mut self, k: K) -> Option<C> {
+        let index = k.index();
+        if !self.bitvec.get(index)? {
+            return None;
+        }
+        self.bitvec.set(index, false);
+        self.len -= 1;
+        self.free.push(index);
+        Some(unsafe { C::read(&mut self.columns, index) })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.bitvec.ones().map(K::from_index)
+    }
+}
+
+impl<K: Key, C: Columns> Default for BvColumns<K, C> {
+    fn default() -> Self {
+        BvColumns::new()
+    }
+}
+
+impl<K, C: Columns> Drop for BvColumns<K, C> {
+    fn drop(&mut self) {
+        if needs_drop::<C>() {
+            for index in self.bitvec.ones() {
+                unsafe { C::drop_at(&mut self.columns, index) };
+            }
+        }
+    }
+}
+
+/// One column of a `BvColumns`, as returned by `columns`.
+pub struct Column<'a, K, T> {
+    bits: &'a BitSet,
+    values: &'a [MaybeUninit<T>],
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<'a, K: Key, T> Column<'a, K, T> {
+    pub fn get(&self, k: K) -> Option<&'a T> {
+        let index = k.index();
+        if self.bits.get(index)? {
+            Some(unsafe { self.values[index].assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> JoinIter<'a, Column<'a, K, T>> {
+        Column {
+            bits: self.bits,
+            values: self.values,
+            marker: PhantomData,
+        }
+        .join()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &'a T> + 'a {
+        let values = self.values;
+        self.bits
+            .ones()
+            .map(move |index| unsafe { values[index].assume_init_ref() })
+    }
+}
+
+impl<'a, K: Key, T> Index<K> for Column<'a, K, T> {
+    type Output = T;
+
+    fn index(&self, k: K) -> &T {
+        self.get(k).expect("invalid BvColumns key")
+    }
+}
+
+/// One column of a `BvColumns`, as returned by `columns_mut`.
+pub struct ColumnMut<'a, K, T> {
+    bits: &'a BitSet,
+    values: &'a mut [MaybeUninit<T>],
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<'a, K: Key, T> ColumnMut<'a, K, T> {
+    pub fn get(&self, k: K) -> Option<&T> {
+        let index = k.index();
+        if self.bits.get(index)? {
+            Some(unsafe { self.values[index].assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, k: K) -> Option<&mut T> {
+        let index = k.index();
+        if self.bits.get(index)? {
+            Some(unsafe { self.values[index].assume_init_mut() })
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> JoinIter<'_, Column<'_, K, T>> {
+        Column {
+            bits: self.bits,
+            values: self.values,
+            marker: PhantomData,
+        }
+        .join()
+    }
+
+    pub fn iter_mut(&mut self) -> JoinIter<'_, ColumnMut<'_, K, T>> {
+        ColumnMut {
+            bits: self.bits,
+            values: self.values,
+            marker: PhantomData,
+        }
+        .join()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> + '_ {
+        self.iter().map(|(_, v)| v)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> + '_ {
+        self.iter_mut().map(|(_, v)| v)
+    }
+}
+
+impl<'a, K: Key, T> Index<K> for ColumnMut<'a, K, T> {
+    type Output = T;
+
+    fn index(&self, k: K) -> &T {
+        self.get(k).expect("invalid BvColumns key")
+    }
+}
+
+impl<'a, K: Key, T> IndexMut<K> for ColumnMut<'a, K, T> {
+    fn index_mut(&mut self, k: K) -> &mut T {
+        self.get_mut(k).expect("invalid BvColumns key")
+    }
+}
+
+/// Columns of one `BvColumns` that can be walked together: a `Column`, a
+/// `ColumnMut`, or a tuple of up to six of them.
+pub trait Join<'a>: sealed::Sealed + Sized {
+    type Key: Key;
+    type Item;
+
+    #[doc(hidden)]
+    fn words(&self) -> &'a [u64];
+
+    /// Reads the row at `index`, which must be occupied. Each index is read at
+    /// most once, so the mutable references handed out do not overlap.
+    #[doc(hidden)]
+    unsafe fn fetch(&mut self, index: usize) -> Self::Item;
+
+    /// Iterates over the rows in key order, yielding the values of these
+    /// columns together. The occupancy bits are scanned once for all of them.
+    ///
+    /// Panics if the columns come from different maps.
+    fn join(self) -> JoinIter<'a, Self> {
+        JoinIter {
+            ones: ones_in(self.words()),
+            columns: self,
+        }
+    }
+}
+
+impl<'a, K, T> sealed::Sealed for Column<'a, K, T> {}
+
+impl<'a, K: Key, T> Join<'a> for Column<'a, K, T> {
+    type Key = K;
+    type Item = &'a T;
+
+    fn words(&self) -> &'a [u64] {
+        self.bits.words()
+    }
+
+    unsafe fn fetch(&mut self, index: usize) -> &'a T {
+        self.values[index].assume_init_ref()
+    }
+}
+
+impl<'a, K, T> sealed::Sealed for ColumnMut<'a, K, T> {}
+
+impl<'a, K: Key, T> Join<'a> for ColumnMut<'a, K, T> {
+    type Key = K;
+    type Item = &'a mut T;
+
+    fn words(&self) -> &'a [u64] {
+        self.bits.words()
+    }
+
+    unsafe fn fetch(&mut self, index: usize) -> &'a mut T {
+        (*self.values.as_mut_ptr().add(index)).assume_init_mut()
+    }
+}
+
+/// Iterator returned by `Join::join`.
+pub struct JoinIter<'a, J> {
+    ones: Ones<'a>,
+    columns: J,
+}
+
+impl<'a, J: Join<'a>> Iterator for JoinIter<'a, J> {
+    type Item = (J::Key, J::Item);
+
+    fn next(&mut self) -> Option<(J::Key, J::Item)> {
+        let index = self.ones.next()?;
+        Some((J::Key::from_index(index), unsafe {
+            self.columns.fetch(index)
+        }))
+    }
+}
+
+macro_rules! impl_join {
+    ($A:ident $(, $T:ident $i:tt)*) => {
+        impl<'a, $A: Join<'a>, $($T: Join<'a, Key = $A::Key>),*> Join<'a> for ($A, $($T,)*) {
+            type Key = $A::Key;
+            type Item = ($A::Item, $($T::Item,)*);
+
+            fn words(&self) -> &'a [u64] {
+                let words = self.0.words();
+                $(
+                    assert!(
+                        ptr::eq(words, self.$i.words()),
+                        "joined columns come from different maps"
+                    );
+                )*
+                words
+            }
+
+            unsafe fn fetch(&mut self, index: usize) -> Self::Item {
+                (self.0.fetch(index), $(self.$i.fetch(index),)*)
+            }
+        }
+    };
+}
+
+impl_join!(A);
+impl_join!(A, B 1);
+impl_join!(A, B 1, C 2);
+impl_join!(A, B 1, C 2, D 3);
+impl_join!(A, B 1, C 2, D 3, E 4);
+impl_join!(A, B 1, C 2, D 3, E 4, F 5);
+
+macro_rules! impl_columns {
+    ($($T:ident $i:tt),+) => {
+        impl<$($T),+> sealed::Sealed for ($($T,)+) {}
+
+        impl<$($T),+> Columns for ($($T,)+) {
+            type Vecs = ($(Vec<MaybeUninit<$T>>,)+);
+
+            fn push_vacant(vecs: &mut Self::Vecs) {
+                $(vecs.$i.push(MaybeUninit::uninit());)+
+            }
+
+            fn write(vecs: &mut Self::Vecs, index: usize, row: Self) {
+                $(vecs.$i[index] = MaybeUninit::new(row.$i);)+
+            }
+
+            unsafe fn read(vecs: &mut Self::Vecs, index: usize) -> Self {
+                ($(vecs.$i[index].assume_init_read(),)+)
+            }
+
+            unsafe fn drop_at(vecs: &mut Self::Vecs, index: usize) {
+                $(vecs.$i[index].assume_init_drop();)+
+            }
+        }
+
+        impl<K: Key, $($T),+> BvColumns<K, ($($T,)+)> {
+            pub fn columns(&self) -> ($(Column<'_, K, $T>,)+) {
+                ($(
+                    Column {
+                        bits: &self.bitvec,
+                        values: &self.columns.$i,
+                        marker: PhantomData,
+                    },
+                )+)
+            }
+
+            pub fn columns_mut(&mut self) -> ($(ColumnMut<'_, K, $T>,)+) {
+                let bits = &self.bitvec;
+                ($(
+                    ColumnMut {
+                        bits,
+                        values: &mut self.columns.$i,
+                        marker: PhantomData,
+                    },
+                )+)
+            }
+
+            pub fn get(&self, k: K) -> Option<($(&$T,)+)> {
+                let index = k.index();
+                if self.bitvec.get(index)? {
+                    Some(($(unsafe { self.columns.$i[index].assume_init_ref() },)+))
+                } else {
+                    None
+                }
+            }
+
+            pub fn get_mut(&mut self, k: K) -> Option<($(&mut $T,)+)> {
+                let index = k.index();
+                if self.bitvec.get(index)? {
+                    Some(($(unsafe { self.columns.$i[index].assume_init_mut() },)+))
+                } else {
+                    None
+                }
+            }
+
+            /// Iterates over whole rows, in key order.
+            pub fn iter(&self) -> JoinIter<'_, ($(Column<'_, K, $T>,)+)> {
+                self.columns().join()
+            }
+
+            pub fn iter_mut(&mut self) -> JoinIter<'_, ($(ColumnMut<'_, K, $T>,)+)> {
+                self.columns_mut().join()
+            }
+        }
+    };
+}
+
+impl_columns!(A 0);
+impl_columns!(A 0, B 1);
+impl_columns!(A 0, B 1, C 2);
+impl_columns!(A 0, B 1, C 2, D 3);
+impl_columns!(A 0, B 1, C 2, D 3, E 4);
+impl_columns!(A 0, B 1, C 2, D 3, E 4, F 5);
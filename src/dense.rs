@@ -0,0 +1,244 @@
+use crate::bitset::BitSet;
+use crate::Key;
+use std::marker::PhantomData;
+use std::ops::{Index, IndexMut};
+use std::slice;
+
+/// A map that keeps its values packed together in one `Vec`, so iterating is
+/// a walk over a slice no matter how many values have been removed.
+///
+/// Removing swaps the last value into the hole, so values are not kept in key
+/// order and the position of a value can change. A lookup goes through an
+/// index table from key to position, which costs one more memory access than
+/// `BvMap`.
+pub struct DenseBvMap<K, V> {
+    // Head of the free list threaded through the vacant entries of `index`.
+    next_free: usize,
+    // For a live key, the position of its value. For a vacant key, the next
+    // vacant key.
+    index: Vec<usize>,
+    bitvec: BitSet,
+    values: Vec<V>,
+    // The key of the value at each position.
+    keys: Vec<usize>,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<K: Key, V> DenseBvMap<K, V> {
+    pub fn new() -> DenseBvMap<K, V> {
+        DenseBvMap {
+            next_free: 0,
+            index: Vec::new(),
+            bitvec: BitSet::new(),
+            values: Vec::new(),
+            keys: Vec::new(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> DenseBvMap<K, V> {
+        DenseBvMap {
+            next_free: 0,
+            index: Vec::with_capacity(capacity),
+            bitvec: BitSet::with_capacity(capacity),
+            values: Vec::with_capacity(capacity),
+            keys: Vec::with_capacity(capacity),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Appends `v` to the end of `as_slice`.
+    pub fn insert(&mut self, v: V) -> K {
+        let k = self.next_free;
+        let key = K::try_from_index(k).expect("DenseBvMap key space exhausted");
+        if k == self.index.len() {
+            self.index.push(self.values.len());
+            self.bitvec.push(true);
+            self.next_free += 1;
+        } else {
+            self.next_free = self.index[k];
+            self.index[k] = self.values.len();
+            self.bitvec.set(k, true);
+        }
+        self.values.push(v);
+        self.keys.push(k);
+        key
+    }
+
+    pub fn contains_key(&self, k: K) -> bool {
+        self.bitvec.get(k.index()) == Some(true)
+    }
+
+    pub fn get(&self, k: K) -> Option<&V> {
+        let k = k.index();
+        if self.bitvec.get(k)? {
+            Some(&self.values[self.index[k]])
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, k: K) -> Option<&mut V> {
+        let k = k.index();
+        if self.bitvec.get(k)? {
+            Some(&mut self.values[self.index[k]])
+        } else {
+            None
+        }
+    }
+
+    /// Removes the value at `k` and moves the last value into its place.
+    pub fn remove(&mut self, k: K) -> Option<V> {
+        let k = k.index();
+        if !self.bitvec.get(k)? {
+            return None;
+        }
+        let position = self.index[k];
+        let v = self.values.swap_remove(position);
+        self.keys.swap_remove(position);
+        if let Some(&moved) = self.keys.get(position) {
+            self.index[moved] = position;
+        }
+        self.bitvec.set(k, false);
+        self.index[k] = self.next_free;
+        self.next_free = k;
+        Some(v)
+    }
+
+    /// The values, in no particular order.
+    pub fn as_slice(&self) -> &[V] {
+        &self.values
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [V] {
+        &mut self.values
+    }
+
+    /// Iterates in the same order as `as_slice`.
+    pub fn iter(&self) -> DenseIter<'_, K, V> {
+        DenseIter {
+            keys: self.keys.iter(),
+            values: self.values.iter(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> DenseIterMut<'_, K, V> {
+        DenseIterMut {
+            keys: self.keys.iter(),
+            values: self.values.iter_mut(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.keys.iter().map(|&k| K::from_index(k))
+    }
+
+    pub fn values(&self) -> slice::Iter<'_, V> {
+        self.values.iter()
+    }
+
+    pub fn values_mut(&mut self) -> slice::IterMut<'_, V> {
+        self.values.iter_mut()
+    }
+}
+
+impl<K: Key, V> Default for DenseBvMap<K, V> {
+    fn default() -> Self {
+        DenseBvMap::new()
+    }
+}
+
+impl<K, V: Clone> Clone for DenseBvMap<K, V> {
+    fn clone(&self) -> Self {
+        DenseBvMap {
+            next_free: self.next_free,
+            index: self.index.clone(),
+            bitvec: self.bitvec.clone(),
+            values: self.values.clone(),
+            keys: self.keys.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<K: Key, V> Index<K> for DenseBvMap<K, V> {
+    type Output = V;
+
+    fn index(&self, k: K) -> &V {
+        self.get(k).expect("invalid DenseBvMap key")
+    }
+}
+
+impl<K: Key, V> IndexMut<K> for DenseBvMap<K, V> {
+    fn index_mut(&mut self, k: K) -> &mut V {
+        self.get_mut(k).expect("invalid DenseBvMap key")
+    }
+}
+
+impl<'a, K: Key, V> IntoIterator for &'a DenseBvMap<K, V> {
+    type Item = (K, &'a V);
+    type IntoIter = DenseIter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, K: Key, V> IntoIterator for &'a mut DenseBvMap<K, V> {
+    type Item = (K, &'a mut V);
+    type IntoIter = DenseIterMut<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+pub struct DenseIter<'a, K, V> {
+    keys: slice::Iter<'a, usize>,
+    values: slice::Iter<'a, V>,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<'a, K: Key, V> Iterator for DenseIter<'a, K, V> {
+    type Item = (K, &'a V);
+
+    fn next(&mut self) -> Option<(K, &'a V)> {
+        Some((K::from_index(*self.keys.next()?), self.values.next()?))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.values.size_hint()
+    }
+}
+
+impl<'a, K: Key, V> ExactSizeIterator for DenseIter<'a, K, V> {}
+
+pub struct DenseIterMut<'a, K, V> {
+    keys: slice::Iter<'a, usize>,
+    values: slice::IterMut<'a, V>,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<'a, K: Key, V> Iterator for DenseIterMut<'a, K, V> {
+    type Item = (K, &'a mut V);
+
+    fn next(&mut self) -> Option<(K, &'a mut V)> {
+        Some((K::from_index(*self.keys.next()?), self.values.next()?))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.values.size_hint()
+    }
+}
+
+impl<'a, K: Key, V> ExactSizeIterator for DenseIterMut<'a, K, V> {}
@@ -0,0 +1,33 @@
+use std::collections::TryReserveError;
+use std::error::Error;
+use std::fmt;
+
+/// Why `BvMap::try_insert` or `BvMap::try_reserve` could not make room.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InsertError {
+    /// The allocator could not provide the memory.
+    AllocFailed(TryReserveError),
+    /// The key type cannot represent the index of another slot.
+    KeySpaceExhausted,
+    /// The map already has as many slots as its capacity limit allows.
+    CapacityLimit,
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InsertError::AllocFailed(e) => write!(f, "allocation failed: {}", e),
+            InsertError::KeySpaceExhausted => write!(f, "key space exhausted"),
+            InsertError::CapacityLimit => write!(f, "capacity limit reached"),
+        }
+    }
+}
+
+impl Error for InsertError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InsertError::AllocFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
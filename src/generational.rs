@@ -0,0 +1,140 @@
+use crate::BvMap;
+use std::marker::PhantomData;
+
+/// Key of a `GenBvMap`: a slot index plus the generation the slot had when the
+/// value was inserted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GenKey {
+    index: usize,
+    generation: u32,
+}
+
+impl GenKey {
+    pub fn index(self) -> usize {
+        self.index
+    }
+
+    pub fn generation(self) -> u32 {
+        self.generation
+    }
+}
+
+/// Handle type for the values of a `GenBvMap`, which stores a `GenKey`.
+///
+/// Implemented for `GenKey`. Use `new_key_type!` with a `GenKey` repr to
+/// declare a distinct key type per map.
+pub trait GenMapKey: Copy {
+    fn from_gen_key(key: GenKey) -> Self;
+
+    fn gen_key(self) -> GenKey;
+}
+
+impl GenMapKey for GenKey {
+    #[inline]
+    fn from_gen_key(key: GenKey) -> GenKey {
+        key
+    }
+
+    #[inline]
+    fn gen_key(self) -> GenKey {
+        self
+    }
+}
+
+/// A `BvMap` that keeps a generation counter per slot, so that keys to removed
+/// values stay invalid after their slot has been reused.
+///
+/// The generation of a slot is bumped on every `remove`. It wraps after 2^32
+/// reuses of the same slot.
+pub struct GenBvMap<K, V> {
+    map: BvMap<usize, V>,
+    generations: Vec<u32>,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<K: GenMapKey, V> GenBvMap<K, V> {
+    pub fn new() -> GenBvMap<K, V> {
+        GenBvMap {
+            map: BvMap::new(),
+            generations: Vec::new(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn insert(&mut self, v: V) -> K {
+        let index = self.map.insert(v);
+        if index == self.generations.len() {
+            self.generations.push(0);
+        }
+        K::from_gen_key(GenKey {
+            index,
+            generation: self.generations[index],
+        })
+    }
+
+    fn live_index(&self, k: K) -> Option<usize> {
+        let k = k.gen_key();
+        if *self.generations.get(k.index)? == k.generation {
+            Some(k.index)
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self, k: K) -> Option<&V> {
+        let index = self.live_index(k)?;
+        self.map.get(index)
+    }
+
+    pub fn get_mut(&mut self, k: K) -> Option<&mut V> {
+        let index = self.live_index(k)?;
+        self.map.get_mut(index)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (K, &V)> {
+        let generations = &self.generations;
+        self.map.iter().map(move |(index, v)| {
+            let generation = generations[index];
+            (K::from_gen_key(GenKey { index, generation }), v)
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (K, &mut V)> {
+        let generations = &self.generations;
+        self.map.iter_mut().map(move |(index, v)| {
+            let generation = generations[index];
+            (K::from_gen_key(GenKey { index, generation }), v)
+        })
+    }
+
+    pub fn remove(&mut self, k: K) -> Option<V> {
+        let index = self.live_index(k)?;
+        let v = self.map.remove(index)?;
+        self.generations[index] = self.generations[index].wrapping_add(1);
+        Some(v)
+    }
+}
+
+impl<K, V: Clone> Clone for GenBvMap<K, V> {
+    fn clone(&self) -> Self {
+        GenBvMap {
+            map: self.map.clone(),
+            generations: self.generations.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<K: GenMapKey, V> Default for GenBvMap<K, V> {
+    fn default() -> Self {
+        GenBvMap::new()
+    }
+}
@@ -0,0 +1,168 @@
+use std::convert::TryFrom;
+use std::num::NonZeroU32;
+
+/// Handle type for the values of a `BvMap`, which stores a slot index.
+///
+/// Implemented for `usize`, `u32`, `u16` and `NonZeroU32`. Use `new_key_type!`
+/// to declare a distinct key type per map, so that keys of one map cannot be
+/// passed to another by mistake.
+pub trait Key: Copy {
+    /// The largest index the key can hold. A map refuses to grow past it, so
+    /// once all these indices are in use, `insert` on any of the maps panics.
+    const MAX_INDEX: usize = usize::MAX;
+
+    /// Only called with indices up to `MAX_INDEX`.
+    fn from_index(index: usize) -> Self;
+
+    fn index(self) -> usize;
+
+    fn try_from_index(index: usize) -> Option<Self> {
+        if index <= Self::MAX_INDEX {
+            Some(Self::from_index(index))
+        } else {
+            None
+        }
+    }
+}
+
+impl Key for usize {
+    #[inline]
+    fn from_index(index: usize) -> usize {
+        index
+    }
+
+    #[inline]
+    fn index(self) -> usize {
+        self
+    }
+}
+
+impl Key for u32 {
+    const MAX_INDEX: usize = u32::MAX as usize;
+
+    #[inline]
+    fn from_index(index: usize) -> u32 {
+        index as u32
+    }
+
+    #[inline]
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Key for u16 {
+    const MAX_INDEX: usize = u16::MAX as usize;
+
+    #[inline]
+    fn from_index(index: usize) -> u16 {
+        index as u16
+    }
+
+    #[inline]
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Stores the index plus one, so that `Option<NonZeroU32>` is still 4 bytes.
+impl Key for NonZeroU32 {
+    const MAX_INDEX: usize = u32::MAX as usize - 1;
+
+    #[inline]
+    fn from_index(index: usize) -> NonZeroU32 {
+        NonZeroU32::new(index as u32 + 1).expect("BvMap key index out of range")
+    }
+
+    #[inline]
+    fn index(self) -> usize {
+        self.get() as usize - 1
+    }
+
+    fn try_from_index(index: usize) -> Option<NonZeroU32> {
+        NonZeroU32::new(u32::try_from(index).ok()?.checked_add(1)?)
+    }
+}
+
+/// Declares one or more `Copy` newtypes implementing `Key`. The index is
+/// stored as a `usize` unless another key type is given in parentheses.
+/// With `GenKey` in parentheses, written as just that name, the newtype
+/// implements `GenMapKey` instead, for use with `GenBvMap`.
+///
+/// The keys do not implement `Default`, because every index may be live.
+///
+/// ```
+/// use bvmap::{new_key_type, BvMap, GenBvMap, GenKey};
+/// use std::mem::size_of;
+/// use std::num::NonZeroU32;
+///
+/// new_key_type! {
+///     pub struct PlayerKey;
+///     struct EnemyKey(u32);
+///     struct ItemKey(NonZeroU32);
+///     struct BulletKey(GenKey);
+/// }
+///
+/// let mut players: BvMap<PlayerKey, &str> = BvMap::new();
+/// let alice = players.insert("alice");
+/// assert_eq!(players[alice], "alice");
+/// assert_eq!(size_of::<Option<ItemKey>>(), 4);
+///
+/// let mut bullets: GenBvMap<BulletKey, f32> = GenBvMap::new();
+/// let shot = bullets.insert(1.5);
+/// bullets.remove(shot);
+/// bullets.insert(2.5);
+/// assert_eq!(bullets.get(shot), None);
+/// ```
+#[macro_export]
+macro_rules! new_key_type {
+    ($(#[$attr:meta])* $vis:vis struct $name:ident(GenKey); $($rest:tt)*) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        $vis struct $name($crate::GenKey);
+
+        impl $crate::GenMapKey for $name {
+            #[inline]
+            fn from_gen_key(key: $crate::GenKey) -> Self {
+                $name(key)
+            }
+
+            #[inline]
+            fn gen_key(self) -> $crate::GenKey {
+                self.0
+            }
+        }
+
+        $crate::new_key_type!($($rest)*);
+    };
+    ($(#[$attr:meta])* $vis:vis struct $name:ident($repr:ty); $($rest:tt)*) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        $vis struct $name($repr);
+
+        impl $crate::Key for $name {
+            const MAX_INDEX: usize = <$repr as $crate::Key>::MAX_INDEX;
+
+            #[inline]
+            fn from_index(index: usize) -> Self {
+                $name(<$repr as $crate::Key>::from_index(index))
+            }
+
+            #[inline]
+            fn index(self) -> usize {
+                <$repr as $crate::Key>::index(self.0)
+            }
+
+            #[inline]
+            fn try_from_index(index: usize) -> Option<Self> {
+                <$repr as $crate::Key>::try_from_index(index).map($name)
+            }
+        }
+
+        $crate::new_key_type!($($rest)*);
+    };
+    ($(#[$attr:meta])* $vis:vis struct $name:ident; $($rest:tt)*) => {
+        $crate::new_key_type!($(#[$attr])* $vis struct $name(usize); $($rest)*);
+    };
+    () => {};
+}
@@ -1,6 +1,53 @@
-use smallbitvec::SmallBitVec;
+mod atomic;
+mod bitset;
+mod chunked;
+mod columns;
+mod dense;
+mod error;
+mod generational;
+mod key;
+#[cfg(feature = "rayon")]
+mod par;
+mod secondary;
+#[cfg(feature = "serde")]
+mod serialize;
+mod sharded;
+mod validate;
+
+pub use atomic::AtomicBvMap;
+pub use chunked::{ChunkedBvMap, ChunkedIter};
+pub use columns::{BvColumns, Column, ColumnMut, Columns, Join, JoinIter};
+pub use dense::{DenseBvMap, DenseIter, DenseIterMut};
+pub use error::InsertError;
+pub use generational::{GenBvMap, GenKey, GenMapKey};
+pub use key::Key;
+#[cfg(feature = "rayon")]
+pub use par::ParDrain;
+pub use secondary::BvSecondaryMap;
+pub use sharded::ShardedBvMap;
+pub use validate::InvariantError;
+
+use bitset::{BitSet, IntoOnes, Ones};
+use std::cmp::min;
+use std::fmt::{self, Debug};
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
 use std::marker::PhantomData;
-use std::mem::{needs_drop, replace, ManuallyDrop};
+use std::mem::{needs_drop, replace, take, ManuallyDrop};
+use std::ops::{Index, IndexMut};
+use std::{slice, vec};
+
+/// How `insert` picks a vacant slot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AllocPolicy {
+    /// Reuse the most recently freed slot. This is the default.
+    #[default]
+    Lifo,
+    /// Reuse the free slot with the lowest index, which keeps values packed
+    /// towards the start of the map and makes iteration after churn cheaper.
+    LowestFirst,
+}
 
 union Slot<V> {
     value: ManuallyDrop<V>,
@@ -9,25 +56,158 @@ union Slot<V> {
 
 #[derive(Default)]
 pub struct BvMap<K, V> {
+    // With `AllocPolicy::Lifo` this is the head of the free list threaded
+    // through the vacant slots. With `AllocPolicy::LowestFirst` it is the lowest
+    // vacant index and the links in vacant slots are unused.
     next_free: usize,
-    bitvec: SmallBitVec,
+    len: usize,
+    policy: AllocPolicy,
+    // Most slots the map may grow to, if limited.
+    limit: Option<usize>,
+    bitvec: BitSet,
     vec: Vec<Slot<V>>,
     marker: PhantomData<fn(K) -> K>,
 }
 
-impl<K: Into<usize> + From<usize>, V> BvMap<K, V> {
+impl<K: Key, V> BvMap<K, V> {
     pub fn new() -> BvMap<K, V> {
         BvMap {
             next_free: 0,
-            bitvec: SmallBitVec::new(),
+            len: 0,
+            policy: AllocPolicy::Lifo,
+            limit: None,
+            bitvec: BitSet::new(),
             vec: Vec::new(),
             marker: PhantomData,
         }
     }
 
+    pub fn with_policy(policy: AllocPolicy) -> BvMap<K, V> {
+        let mut map = BvMap::new();
+        map.policy = policy;
+        map
+    }
+
+    /// Creates a map that never holds more than `limit` slots. Inserting
+    /// beyond that fails with `InsertError::CapacityLimit`.
+    pub fn with_capacity_limit(limit: usize) -> BvMap<K, V> {
+        let mut map = BvMap::new();
+        map.limit = Some(limit);
+        map
+    }
+
+    pub fn with_capacity(capacity: usize) -> BvMap<K, V> {
+        BvMap {
+            next_free: 0,
+            len: 0,
+            policy: AllocPolicy::Lifo,
+            limit: None,
+            bitvec: BitSet::with_capacity(capacity),
+            vec: Vec::with_capacity(capacity),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn policy(&self) -> AllocPolicy {
+        self.policy
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.vec.capacity()
+    }
+
+    pub fn capacity_limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Changes the allocation policy. The vacant slots are relinked in
+    /// increasing order, which takes time linear in the number of slots.
+    pub fn set_policy(&mut self, policy: AllocPolicy) {
+        self.policy = policy;
+        let mut next_free = self.vec.len();
+        for index in (0..self.vec.len()).rev() {
+            if !self.bitvec[index] {
+                self.vec[index] = Slot { next_free };
+                next_free = index;
+            }
+        }
+        self.next_free = next_free;
+        self.check_invariants();
+    }
+
+    /// Sets the most slots the map may grow to, or lifts the limit. Slots the
+    /// map already has are kept even if they are more than `limit`.
+    pub fn set_capacity_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    /// Reserves room for at least `additional` more values. Vacant slots count
+    /// towards the room, so this only allocates once they are used up.
+    pub fn reserve(&mut self, additional: usize) {
+        let vacant = self.vec.len() - self.len;
+        if additional > vacant {
+            self.vec.reserve(additional - vacant);
+            self.bitvec.reserve(additional - vacant);
+        }
+    }
+
+    /// Like `reserve`, but returns an error instead of panicking or aborting
+    /// when the room cannot be made.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), InsertError> {
+        let vacant = self.vec.len() - self.len;
+        if additional > vacant {
+            let grow = additional - vacant;
+            let slots = self.vec.len().saturating_add(grow);
+            if slots - 1 > K::MAX_INDEX {
+                return Err(InsertError::KeySpaceExhausted);
+            }
+            if self.limit.is_some_and(|limit| slots > limit) {
+                return Err(InsertError::CapacityLimit);
+            }
+            self.vec
+                .try_reserve(grow)
+                .map_err(InsertError::AllocFailed)?;
+            self.bitvec
+                .try_reserve(grow)
+                .map_err(InsertError::AllocFailed)?;
+        }
+        Ok(())
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.vec.shrink_to_fit();
+        self.bitvec.shrink_to_fit();
+    }
+
+    /// Panics if the map has no room for `v`, either because of its capacity
+    /// limit or the key type. `try_insert` returns the error instead.
     pub fn insert(&mut self, v: V) -> K {
+        match self.try_insert(v) {
+            Ok(key) => key,
+            Err((e, _)) => panic!("BvMap insert failed: {}", e),
+        }
+    }
+
+    /// Inserts `v`, or returns an error together with `v` if there is no room
+    /// for it. Never panics or aborts on allocation failure.
+    pub fn try_insert(&mut self, v: V) -> Result<K, (InsertError, V)> {
+        let key = match K::try_from_index(self.next_free) {
+            Some(key) => key,
+            None => return Err((InsertError::KeySpaceExhausted, v)),
+        };
         let next_free = self.next_free;
         if next_free == self.vec.len() {
+            if let Err(e) = self.try_reserve(1) {
+                return Err((e, v));
+            }
             self.vec.push(Slot {
                 value: ManuallyDrop::new(v),
             });
@@ -40,14 +220,68 @@ impl<K: Into<usize> + From<usize>, V> BvMap<K, V> {
                     value: ManuallyDrop::new(v),
                 },
             );
-            self.next_free = unsafe { slot.next_free };
+            self.next_free = match self.policy {
+                AllocPolicy::Lifo => unsafe { slot.next_free },
+                AllocPolicy::LowestFirst => self
+                    .bitvec
+                    .first_zero_from(next_free + 1)
+                    .unwrap_or(self.vec.len()),
+            };
             self.bitvec.set(next_free, true);
         }
-        K::from(next_free)
+        self.len += 1;
+        self.check_invariants();
+        Ok(key)
+    }
+
+    // Makes room for one more value and returns the key it will get, so the
+    // insert that follows cannot fail.
+    fn next_key(&mut self) -> Result<K, InsertError> {
+        let key = K::try_from_index(self.next_free).ok_or(InsertError::KeySpaceExhausted)?;
+        if self.next_free == self.vec.len() {
+            self.try_reserve(1)?;
+        }
+        Ok(key)
+    }
+
+    /// Inserts the value returned by `f`, which is given the key the value
+    /// will be stored under.
+    ///
+    /// Panics if there is no room for another value. `f` is not called then.
+    pub fn insert_with_key<F: FnOnce(K) -> V>(&mut self, f: F) -> K {
+        match self.try_insert_with_key(f) {
+            Ok(key) => key,
+            Err(e) => panic!("BvMap insert failed: {}", e),
+        }
+    }
+
+    /// Like `insert_with_key`, but returns an error without calling `f` if
+    /// there is no room for another value.
+    pub fn try_insert_with_key<F: FnOnce(K) -> V>(&mut self, f: F) -> Result<K, InsertError> {
+        let v = f(self.next_key()?);
+        Ok(self.insert(v))
+    }
+
+    /// Returns a handle to the slot the next `insert` will use, so that its key
+    /// can be looked at before the value is created.
+    ///
+    /// Panics if there is no room for another value.
+    pub fn vacant_entry(&mut self) -> VacantEntry<'_, K, V> {
+        match self.try_vacant_entry() {
+            Ok(entry) => entry,
+            Err(e) => panic!("BvMap insert failed: {}", e),
+        }
+    }
+
+    /// Like `vacant_entry`, but returns an error if there is no room for
+    /// another value.
+    pub fn try_vacant_entry(&mut self) -> Result<VacantEntry<'_, K, V>, InsertError> {
+        let key = self.next_key()?;
+        Ok(VacantEntry { key, map: self })
     }
 
     pub fn get(&self, k: K) -> Option<&V> {
-        let k = k.into();
+        let k = k.index();
         self.bitvec.get(k).and_then(|o| {
             if o {
                 Some(unsafe { &*self.vec[k].value })
@@ -57,37 +291,310 @@ impl<K: Into<usize> + From<usize>, V> BvMap<K, V> {
         })
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &V> {
-        self.vec
-            .iter()
-            .zip(self.bitvec.iter())
-            .filter_map(|(slot, occupied)| {
-                if occupied {
-                    Some(unsafe { &*slot.value })
-                } else {
-                    None
-                }
-            })
+    pub fn get_mut(&mut self, k: K) -> Option<&mut V> {
+        let k = k.index();
+        if self.bitvec.get(k)? {
+            Some(unsafe { &mut *self.vec[k].value })
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            ones: self.bitvec.ones(),
+            slots: self.vec.iter(),
+            next_index: 0,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut {
+            ones: self.bitvec.ones(),
+            slots: self.vec.iter_mut(),
+            next_index: 0,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.bitvec.ones().map(K::from_index)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.iter_mut().map(|(_, v)| v)
     }
 
     pub fn remove(&mut self, k: K) -> Option<V> {
-        let k = k.into();
+        let k = k.index();
         if self.bitvec.get(k)? {
             self.bitvec.set(k, false);
-            let next_free = replace(&mut self.next_free, k);
+            self.len -= 1;
+            let next_free = match self.policy {
+                AllocPolicy::Lifo => replace(&mut self.next_free, k),
+                AllocPolicy::LowestFirst => {
+                    self.next_free = min(self.next_free, k);
+                    0
+                }
+            };
             let slot = replace(&mut self.vec[k], Slot { next_free });
+            self.check_invariants();
             Some(ManuallyDrop::into_inner(unsafe { slot.value }))
         } else {
             None
         }
     }
+
+    pub fn retain<F: FnMut(K, &mut V) -> bool>(&mut self, mut f: F) {
+        for index in 0..self.vec.len() {
+            if self.bitvec[index]
+                && !f(K::from_index(index), unsafe { &mut *self.vec[index].value })
+            {
+                self.remove(K::from_index(index));
+            }
+        }
+    }
+
+    /// Moves all values to the lowest slots and drops the vacant slots left
+    /// behind. `f` is called with the old and new key of every value that
+    /// moved, and the same pairs are returned. The map is fully rebuilt before
+    /// `f` is first called, so it stays valid if `f` panics.
+    pub fn compact<F: FnMut(K, K)>(&mut self, mut f: F) -> Vec<(K, K)> {
+        let mut moves = Vec::new();
+        let mut from = self.vec.len();
+        for to in 0..self.len {
+            if !self.bitvec[to] {
+                from -= 1;
+                while !self.bitvec[from] {
+                    from -= 1;
+                }
+                self.vec[to] = replace(&mut self.vec[from], Slot { next_free: 0 });
+                self.bitvec.set(to, true);
+                self.bitvec.set(from, false);
+                moves.push((from, to));
+            }
+        }
+        self.vec.truncate(self.len);
+        self.bitvec.truncate(self.len);
+        self.next_free = self.len;
+        self.check_invariants();
+
+        for &(from, to) in &moves {
+            f(K::from_index(from), K::from_index(to));
+        }
+        moves
+            .into_iter()
+            .map(|(from, to)| (K::from_index(from), K::from_index(to)))
+            .collect()
+    }
+
+    /// Drops all values. The map keeps its allocation, so it can be refilled
+    /// without growing again.
+    pub fn clear(&mut self) {
+        self.clear_slots();
+        self.check_invariants();
+    }
+
+    /// Removes all values, yielding them with their keys. The map is empty
+    /// afterwards even if the iterator is not run to completion, and keeps
+    /// its allocation.
+    pub fn drain(&mut self) -> Drain<'_, K, V> {
+        Drain {
+            map: self,
+            next_index: 0,
+        }
+    }
+}
+
+pub struct Iter<'a, K, V> {
+    ones: Ones<'a>,
+    slots: slice::Iter<'a, Slot<V>>,
+    next_index: usize,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<'a, K: Key, V> Iterator for Iter<'a, K, V> {
+    type Item = (K, &'a V);
+
+    fn next(&mut self) -> Option<(K, &'a V)> {
+        let index = self.ones.next()?;
+        let slot = self.slots.nth(index - self.next_index)?;
+        self.next_index = index + 1;
+        Some((K::from_index(index), unsafe { &*slot.value }))
+    }
+}
+
+pub struct IterMut<'a, K, V> {
+    ones: Ones<'a>,
+    slots: slice::IterMut<'a, Slot<V>>,
+    next_index: usize,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<'a, K: Key, V> Iterator for IterMut<'a, K, V> {
+    type Item = (K, &'a mut V);
+
+    fn next(&mut self) -> Option<(K, &'a mut V)> {
+        let index = self.ones.next()?;
+        let slot = self.slots.nth(index - self.next_index)?;
+        self.next_index = index + 1;
+        Some((K::from_index(index), unsafe { &mut *slot.value }))
+    }
+}
+
+pub struct IntoIter<K, V> {
+    ones: IntoOnes,
+    slots: vec::IntoIter<Slot<V>>,
+    next_index: usize,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<K, V> IntoIter<K, V> {
+    fn next_occupied(&mut self) -> Option<(usize, V)> {
+        let index = self.ones.next()?;
+        let slot = self.slots.nth(index - self.next_index)?;
+        self.next_index = index + 1;
+        Some((index, ManuallyDrop::into_inner(unsafe { slot.value })))
+    }
+}
+
+impl<K: Key, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.next_occupied()
+            .map(|(index, v)| (K::from_index(index), v))
+    }
+}
+
+impl<K, V> Drop for IntoIter<K, V> {
+    fn drop(&mut self) {
+        if needs_drop::<V>() {
+            while self.next_occupied().is_some() {}
+        }
+    }
+}
+
+pub struct Drain<'a, K, V> {
+    map: &'a mut BvMap<K, V>,
+    next_index: usize,
+}
+
+impl<'a, K: Key, V> Iterator for Drain<'a, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        let map = &mut *self.map;
+        let index = map.bitvec.first_one_from(self.next_index)?;
+        self.next_index = index + 1;
+        // The slot is left out of the free list, because dropping the
+        // iterator clears the whole map.
+        map.bitvec.set(index, false);
+        map.len -= 1;
+        let slot = replace(&mut map.vec[index], Slot { next_free: 0 });
+        Some((
+            K::from_index(index),
+            ManuallyDrop::into_inner(unsafe { slot.value }),
+        ))
+    }
+}
+
+impl<'a, K, V> Drop for Drain<'a, K, V> {
+    fn drop(&mut self) {
+        self.map.clear_slots();
+    }
+}
+
+pub struct VacantEntry<'a, K, V> {
+    key: K,
+    map: &'a mut BvMap<K, V>,
+}
+
+impl<'a, K: Key, V> VacantEntry<'a, K, V> {
+    pub fn key(&self) -> K {
+        self.key
+    }
+
+    pub fn insert(self, v: V) -> &'a mut V {
+        let k = self.map.insert(v);
+        self.map.get_mut(k).unwrap()
+    }
+}
+
+impl<K: Key, V> Index<K> for BvMap<K, V> {
+    type Output = V;
+
+    fn index(&self, k: K) -> &V {
+        self.get(k).expect("invalid BvMap key")
+    }
+}
+
+impl<K: Key, V> IndexMut<K> for BvMap<K, V> {
+    fn index_mut(&mut self, k: K) -> &mut V {
+        self.get_mut(k).expect("invalid BvMap key")
+    }
+}
+
+impl<K: Key, V> IntoIterator for BvMap<K, V> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(mut self) -> IntoIter<K, V> {
+        IntoIter {
+            ones: take(&mut self.bitvec).into_iter(),
+            slots: take(&mut self.vec).into_iter(),
+            next_index: 0,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, K: Key, V> IntoIterator for &'a BvMap<K, V> {
+    type Item = (K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<'a, K: Key, V> IntoIterator for &'a mut BvMap<K, V> {
+    type Item = (K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> IterMut<'a, K, V> {
+        self.iter_mut()
+    }
+}
+
+impl<K: Key, V> FromIterator<V> for BvMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = V>>(iter: I) -> Self {
+        let mut map = BvMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: Key, V> Extend<V> for BvMap<K, V> {
+    fn extend<I: IntoIterator<Item = V>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        for v in iter {
+            self.insert(v);
+        }
+    }
 }
 
 impl<K, V: Clone> Clone for BvMap<K, V> {
     fn clone(&self) -> Self {
         let mut vec: Vec<Slot<V>> = Vec::with_capacity(self.vec.len());
-        for (slot, occupied) in self.vec.iter().zip(self.bitvec.iter()) {
-            vec.push(if occupied {
+        for (index, slot) in self.vec.iter().enumerate() {
+            vec.push(if self.bitvec[index] {
                 Slot {
                     value: unsafe { &slot.value }.clone(),
                 }
@@ -101,18 +608,77 @@ impl<K, V: Clone> Clone for BvMap<K, V> {
             vec,
             bitvec: self.bitvec.clone(),
             next_free: self.next_free,
+            len: self.len,
+            policy: self.policy,
+            limit: self.limit,
             marker: PhantomData,
         }
     }
 }
 
+impl<K: Key + Debug, V: Debug> Debug for BvMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+// Equality and hashing only look at the occupied slots, so two maps holding the
+// same values under the same keys compare equal whatever their free lists are.
+impl<K, V: PartialEq> PartialEq for BvMap<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len
+            && self
+                .bitvec
+                .ones()
+                .zip(other.bitvec.ones())
+                .all(|(a, b)| a == b && unsafe { *self.vec[a].value == *other.vec[b].value })
+    }
+}
+
+impl<K, V: Eq> Eq for BvMap<K, V> {}
+
+impl<K, V: Hash> Hash for BvMap<K, V> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len.hash(state);
+        for index in self.bitvec.ones() {
+            index.hash(state);
+            unsafe { &*self.vec[index].value }.hash(state);
+        }
+    }
+}
+
+impl<K, V> BvMap<K, V> {
+    // Drops the values still marked occupied and empties the map without
+    // freeing its allocation. If a destructor panics, the map is emptied all
+    // the same and the values not yet dropped are leaked.
+    pub(crate) fn clear_slots(&mut self) {
+        struct Reset<'a, K, V>(&'a mut BvMap<K, V>);
+
+        impl<'a, K, V> Drop for Reset<'a, K, V> {
+            fn drop(&mut self) {
+                let map = &mut *self.0;
+                map.vec.clear();
+                map.bitvec.clear();
+                map.next_free = 0;
+                map.len = 0;
+            }
+        }
+
+        let reset = Reset(self);
+        let map = &mut *reset.0;
+        if needs_drop::<V>() {
+            for index in map.bitvec.ones() {
+                unsafe { ManuallyDrop::drop(&mut map.vec[index].value) };
+            }
+        }
+    }
+}
+
 impl<K, V> Drop for BvMap<K, V> {
     fn drop(&mut self) {
         if needs_drop::<V>() {
-            for (slot, occupied) in self.vec.drain(..).zip(self.bitvec.iter()) {
-                if occupied {
-                    let _ = ManuallyDrop::into_inner(unsafe { slot.value });
-                }
+            for index in self.bitvec.ones() {
+                unsafe { ManuallyDrop::drop(&mut self.vec[index].value) };
             }
         }
     }
@@ -120,7 +686,52 @@ impl<K, V> Drop for BvMap<K, V> {
 
 #[cfg(test)]
 mod tests {
-    use crate::BvMap;
+    use crate::{
+        new_key_type, AllocPolicy, AtomicBvMap, BvColumns, BvMap, BvSecondaryMap, ChunkedBvMap,
+        DenseBvMap, GenBvMap, GenKey, InsertError, Join, Key, ShardedBvMap,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Shared count of the `Counted` values dropped so far.
+    #[derive(Clone, Default)]
+    struct Drops(Arc<AtomicUsize>);
+
+    impl Drops {
+        fn get(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+
+        fn counted(&self) -> Counted {
+            Counted {
+                drops: self.clone(),
+                panics: false,
+            }
+        }
+
+        // Counts its drop like the others, then panics.
+        fn bomb(&self) -> Counted {
+            Counted {
+                drops: self.clone(),
+                panics: true,
+            }
+        }
+    }
+
+    struct Counted {
+        drops: Drops,
+        panics: bool,
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.drops.0.fetch_add(1, Ordering::SeqCst);
+            if self.panics {
+                panic!("boom");
+            }
+        }
+    }
+
     #[test]
     fn basic() {
         let mut bvmap: BvMap<usize, usize> = BvMap::new();
@@ -131,4 +742,932 @@ mod tests {
         assert_eq!(bvmap.remove(a2), Some(12));
         assert_eq!(bvmap.get(a2), None);
     }
+
+    #[test]
+    fn mutate_in_place() {
+        let mut bvmap: BvMap<usize, usize> = BvMap::new();
+        let a1 = bvmap.insert(11);
+        let a2 = bvmap.insert(12);
+        let a3 = bvmap.insert(13);
+        bvmap.remove(a2);
+        *bvmap.get_mut(a1).unwrap() += 100;
+        bvmap[a3] += 200;
+        assert_eq!(bvmap.get_mut(a2), None);
+        assert_eq!(bvmap[a1], 111);
+        assert_eq!(bvmap[a3], 213);
+        for v in bvmap.values_mut() {
+            *v += 1;
+        }
+        assert_eq!(bvmap.values().copied().collect::<Vec<_>>(), vec![112, 214]);
+    }
+
+    #[test]
+    fn keyed_iteration() {
+        let mut bvmap: BvMap<usize, usize> = BvMap::new();
+        let a1 = bvmap.insert(11);
+        let a2 = bvmap.insert(12);
+        let a3 = bvmap.insert(13);
+        bvmap.remove(a2);
+        assert_eq!(bvmap.keys().collect::<Vec<_>>(), vec![a1, a3]);
+        for (k, v) in bvmap.iter_mut() {
+            *v += k;
+        }
+        assert_eq!(
+            bvmap.iter().collect::<Vec<_>>(),
+            vec![(a1, &(11 + a1)), (a3, &(13 + a3))]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn index_vacant() {
+        let mut bvmap: BvMap<usize, usize> = BvMap::new();
+        let a1 = bvmap.insert(11);
+        bvmap.remove(a1);
+        let _ = bvmap[a1];
+    }
+
+    #[test]
+    fn len_and_capacity() {
+        let mut bvmap: BvMap<usize, usize> = BvMap::with_capacity(4);
+        assert!(bvmap.is_empty());
+        assert!(bvmap.capacity() >= 4);
+        let a1 = bvmap.insert(11);
+        bvmap.insert(12);
+        assert_eq!(bvmap.len(), 2);
+        bvmap.remove(a1);
+        bvmap.remove(a1);
+        assert_eq!(bvmap.len(), 1);
+        bvmap.reserve(10);
+        assert!(bvmap.capacity() >= 11);
+        bvmap.shrink_to_fit();
+        assert_eq!(bvmap.capacity(), 2);
+        assert_eq!(bvmap.insert(13), a1);
+        assert_eq!(bvmap.len(), 2);
+        assert_eq!(bvmap.clone().len(), 2);
+    }
+
+    #[test]
+    fn values_that_know_their_key() {
+        let mut bvmap: BvMap<usize, (usize, &str)> = BvMap::new();
+        let a1 = bvmap.insert_with_key(|k| (k, "a1"));
+        let a2 = bvmap.insert((99, "a2"));
+        bvmap.remove(a2);
+        let entry = bvmap.vacant_entry();
+        let a3 = entry.key();
+        assert_eq!(a3, a2);
+        entry.insert((a3, "a3")).1 = "a3!";
+        assert_eq!(bvmap[a1], (a1, "a1"));
+        assert_eq!(bvmap[a3], (a3, "a3!"));
+        assert_eq!(bvmap.vacant_entry().key(), 2);
+    }
+
+    #[test]
+    fn bulk_removal() {
+        let mut bvmap: BvMap<usize, usize> = BvMap::new();
+        for v in 0..10 {
+            bvmap.insert(v);
+        }
+        bvmap.retain(|k, v| {
+            *v *= 10;
+            [0, 3, 6, 9].contains(&k)
+        });
+        assert_eq!(bvmap.len(), 4);
+        assert_eq!(
+            bvmap.iter().collect::<Vec<_>>(),
+            vec![(0, &0), (3, &30), (6, &60), (9, &90)]
+        );
+        let a = bvmap.insert(100);
+        let b = bvmap.insert(101);
+        assert!(a != b && bvmap[a] == 100 && bvmap[b] == 101);
+        assert!(![0, 3, 6, 9].contains(&a) && ![0, 3, 6, 9].contains(&b));
+
+        let capacity = bvmap.capacity();
+        let drained = bvmap.drain().collect::<Vec<_>>();
+        assert_eq!(drained.len(), 6);
+        assert_eq!(drained[0], (0, 0));
+        assert!(bvmap.is_empty());
+        assert_eq!(bvmap.capacity(), capacity);
+        assert_eq!(bvmap.insert(7), 0);
+
+        bvmap.insert(8);
+        bvmap.drain().next();
+        assert!(bvmap.is_empty());
+        assert_eq!(bvmap.get(0), None);
+        bvmap.insert(9);
+        bvmap.clear();
+        assert_eq!(bvmap.len(), 0);
+        assert_eq!(bvmap.iter().count(), 0);
+        assert_eq!(bvmap.insert(10), 0);
+
+        let mut bvmap: BvMap<usize, usize> = (0..10000).collect();
+        bvmap.clear();
+        assert!(bvmap.capacity() >= 10000);
+        bvmap.extend(0..10000);
+        bvmap.drain().next();
+        assert!(bvmap.is_empty());
+        assert!(bvmap.capacity() >= 10000);
+    }
+
+    #[test]
+    fn into_iter_and_collect() {
+        let mut bvmap: BvMap<usize, usize> = (10..15).collect();
+        bvmap.remove(1);
+        bvmap.extend(vec![20, 21]);
+        assert_eq!(bvmap.len(), 6);
+        for (_, v) in &mut bvmap {
+            *v += 1;
+        }
+        let mut keys = Vec::new();
+        for (k, _) in &bvmap {
+            keys.push(k);
+        }
+        assert_eq!(keys, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(
+            bvmap.into_iter().collect::<Vec<_>>(),
+            vec![(0, 11), (1, 21), (2, 13), (3, 14), (4, 15), (5, 22)]
+        );
+
+        let drops = Drops::default();
+        let mut bvmap: BvMap<usize, Counted> = (0..4).map(|_| drops.counted()).collect();
+        bvmap.remove(1);
+        let mut into_iter = bvmap.into_iter();
+        assert_eq!(into_iter.next().map(|(k, _)| k), Some(0));
+        assert_eq!(drops.get(), 2);
+        drop(into_iter);
+        assert_eq!(drops.get(), 4);
+    }
+
+    #[test]
+    fn sparse_iteration() {
+        let mut bvmap: BvMap<usize, usize> = (0..300).collect();
+        let kept = vec![0, 63, 64, 65, 191, 192, 299];
+        bvmap.retain(|k, _| kept.contains(&k));
+        assert_eq!(bvmap.keys().collect::<Vec<_>>(), kept);
+        assert_eq!(bvmap.values().copied().collect::<Vec<_>>(), kept);
+        for (k, v) in bvmap.iter_mut() {
+            *v += k;
+        }
+        assert_eq!(bvmap.get(299), Some(&598));
+        assert_eq!(bvmap.get(300), None);
+        assert_eq!(bvmap.drain().map(|(k, _)| k).collect::<Vec<_>>(), kept);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let mut bvmap: BvMap<usize, String> = BvMap::new();
+        for v in 0..6 {
+            bvmap.insert(v.to_string());
+        }
+        bvmap.remove(4);
+        bvmap.remove(1);
+        bvmap.remove(2);
+        let json = serde_json::to_string(&bvmap).unwrap();
+        let mut copy: BvMap<usize, String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(copy.len(), 3);
+        assert_eq!(
+            copy.iter().collect::<Vec<_>>(),
+            bvmap.iter().collect::<Vec<_>>()
+        );
+        for v in 6..10 {
+            assert_eq!(copy.insert(v.to_string()), bvmap.insert(v.to_string()));
+        }
+
+        let cyclic = r#"{"next_free":0,"slots":[{"Vacant":1},{"Vacant":0}]}"#;
+        assert!(serde_json::from_str::<BvMap<usize, String>>(cyclic).is_err());
+        let occupied = r#"{"next_free":0,"slots":[{"Occupied":"a"}]}"#;
+        assert!(serde_json::from_str::<BvMap<usize, String>>(occupied).is_err());
+
+        let mut bvmap: BvMap<usize, usize> = BvMap::with_policy(AllocPolicy::LowestFirst);
+        bvmap.extend(0..5);
+        bvmap.remove(3);
+        bvmap.remove(1);
+        let json = serde_json::to_string(&bvmap).unwrap();
+        let mut copy: BvMap<usize, usize> = serde_json::from_str(&json).unwrap();
+        assert_eq!(copy.policy(), AllocPolicy::LowestFirst);
+        assert_eq!(copy.insert(10), 1);
+        let skipping = json.replace(r#""next_free":1"#, r#""next_free":3"#);
+        assert!(serde_json::from_str::<BvMap<usize, usize>>(&skipping).is_err());
+
+        let mut limited: BvMap<usize, usize> = BvMap::with_capacity_limit(2);
+        limited.insert(7);
+        let json = serde_json::to_string(&limited).unwrap();
+        let mut copy: BvMap<usize, usize> = serde_json::from_str(&json).unwrap();
+        assert_eq!(copy.capacity_limit(), Some(2));
+        copy.insert(8);
+        assert_eq!(copy.try_insert(9), Err((InsertError::CapacityLimit, 9)));
+        // Maps written before the limit was serialized have none.
+        let unlimited = r#"{"next_free":1,"slots":[{"Occupied":7}]}"#;
+        let copy: BvMap<usize, usize> = serde_json::from_str(unlimited).unwrap();
+        assert_eq!(copy.capacity_limit(), None);
+
+        // Too many slots for the keys to tell apart.
+        let json = format!(
+            r#"{{"next_free":70000,"slots":[{}]}}"#,
+            vec![r#"{"Occupied":0}"#; 70000].join(",")
+        );
+        assert!(serde_json::from_str::<BvMap<u32, u8>>(&json).is_ok());
+        assert!(serde_json::from_str::<BvMap<u16, u8>>(&json).is_err());
+    }
+
+    #[test]
+    fn compaction() {
+        let mut bvmap: BvMap<usize, usize> = (0..100).collect();
+        bvmap.retain(|k, _| [3, 50, 70, 71, 99].contains(&k));
+        let mut reported = Vec::new();
+        let remap = bvmap.compact(|from, to| reported.push((from, to)));
+        assert_eq!(remap, vec![(99, 0), (71, 1), (70, 2), (50, 4)]);
+        assert_eq!(reported, remap);
+        assert!(bvmap.capacity() >= 5);
+        assert_eq!(
+            bvmap.iter().collect::<Vec<_>>(),
+            vec![(0, &99), (1, &71), (2, &70), (3, &3), (4, &50)]
+        );
+        assert_eq!(bvmap.insert(100), 5);
+        assert_eq!(bvmap.compact(|_, _| panic!()), vec![]);
+    }
+
+    #[test]
+    fn compaction_survives_panicking_callback() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let mut bvmap: BvMap<usize, String> = (0..10).map(|v| v.to_string()).collect();
+        bvmap.retain(|k, _| k >= 5);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            bvmap.compact(|_, _| panic!("callback failed"));
+        }));
+        assert!(result.is_err());
+        assert_eq!(bvmap.len(), 5);
+        assert_eq!(bvmap.keys().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(bvmap.insert("10".to_string()), 5);
+    }
+
+    #[test]
+    fn lowest_first_policy() {
+        let mut bvmap: BvMap<usize, usize> = BvMap::with_policy(AllocPolicy::LowestFirst);
+        for v in 0..200 {
+            bvmap.insert(v);
+        }
+        for k in &[150, 3, 70, 64, 199] {
+            bvmap.remove(*k);
+        }
+        assert_eq!(bvmap.vacant_entry().key(), 3);
+        let keys = (0..6).map(|v| bvmap.insert(v)).collect::<Vec<_>>();
+        assert_eq!(keys, vec![3, 64, 70, 150, 199, 200]);
+        bvmap.remove(10);
+        bvmap.remove(5);
+        assert_eq!(bvmap.insert_with_key(|k| k), 5);
+        assert_eq!(bvmap.clone().insert(0), 10);
+        bvmap.retain(|k, _| k >= 100);
+        assert_eq!(bvmap.insert(0), 0);
+        assert_eq!(bvmap.insert(1), 1);
+        assert_eq!(bvmap.policy(), AllocPolicy::LowestFirst);
+    }
+
+    #[test]
+    fn non_copy_values() {
+        let mut bvmap: BvMap<usize, Vec<String>> = BvMap::new();
+        let a1 = bvmap.insert(vec!["a".to_string(); 3]);
+        let a2 = bvmap.insert(Vec::new());
+        let a3 = bvmap.insert(vec!["c".to_string()]);
+        assert_eq!(bvmap.remove(a2), Some(Vec::new()));
+        bvmap[a1].push("b".to_string());
+        let copy = bvmap.clone();
+        bvmap.remove(a1);
+        assert_eq!(copy[a1].len(), 4);
+        assert_eq!(
+            copy.into_iter()
+                .map(|(_, v)| v.concat())
+                .collect::<Vec<_>>(),
+            vec!["aaab".to_string(), "c".to_string()]
+        );
+        assert_eq!(bvmap.compact(|_, _| {}), vec![(a3, a1)]);
+        assert_eq!(bvmap[a1], vec!["c".to_string()]);
+    }
+
+    #[test]
+    fn drops_each_value_once() {
+        let drops = Drops::default();
+        let mut bvmap: BvMap<usize, Counted> = BvMap::new();
+        for _ in 0..100 {
+            bvmap.insert(drops.counted());
+        }
+        bvmap.remove(10);
+        bvmap.remove(70);
+        assert_eq!(drops.get(), 2);
+        bvmap.retain(|k, _| k < 50);
+        assert_eq!(drops.get(), 51);
+        bvmap.insert(drops.counted());
+        bvmap.compact(|_, _| {});
+        assert_eq!(drops.get(), 51);
+        bvmap.drain().take(3).for_each(drop);
+        assert_eq!(drops.get(), 101);
+        for _ in 0..10 {
+            bvmap.insert(drops.counted());
+        }
+        let mut into_iter = bvmap.into_iter();
+        into_iter.next();
+        assert_eq!(drops.get(), 102);
+        drop(into_iter);
+        assert_eq!(drops.get(), 111);
+    }
+
+    #[test]
+    fn zero_sized_values() {
+        let mut bvmap: BvMap<usize, ()> = BvMap::new();
+        for _ in 0..100 {
+            bvmap.insert(());
+        }
+        assert_eq!(bvmap.remove(40), Some(()));
+        assert_eq!(bvmap.remove(40), None);
+        assert_eq!(bvmap.get(41), Some(&()));
+        assert_eq!(bvmap.insert(()), 40);
+        bvmap.retain(|k, _| k >= 90);
+        assert_eq!(bvmap.len(), 10);
+        assert_eq!(
+            bvmap.clone().keys().collect::<Vec<_>>(),
+            (90..100).collect::<Vec<_>>()
+        );
+        assert_eq!(bvmap.drain().count(), 10);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_iteration() {
+        use rayon::prelude::*;
+
+        let mut bvmap: BvMap<usize, usize> = (0..1000).collect();
+        bvmap.retain(|k, _| k % 7 != 0);
+        let expected: usize = bvmap.iter().map(|(k, v)| k + v).sum();
+        assert_eq!(
+            bvmap.par_iter().map(|(k, v)| k + v).sum::<usize>(),
+            expected
+        );
+        bvmap.par_iter_mut().for_each(|(k, v)| *v += k);
+        assert_eq!(bvmap.values().sum::<usize>(), expected);
+        let capacity = bvmap.capacity();
+        let mut drained = bvmap.par_drain().collect::<Vec<_>>();
+        drained.sort();
+        assert_eq!(drained.len(), 857);
+        assert_eq!(drained[1], (2, 4));
+        assert!(bvmap.is_empty());
+        assert_eq!(bvmap.capacity(), capacity);
+        assert_eq!(bvmap.insert(0), 0);
+
+        let drops = Drops::default();
+        let mut bvmap: BvMap<usize, Counted> = BvMap::new();
+        for _ in 0..1000 {
+            bvmap.insert(drops.counted());
+        }
+        bvmap.remove(500);
+        assert!(bvmap.par_drain().any(|(k, _)| k == 20));
+        assert_eq!(drops.get(), 1000);
+        bvmap.insert(drops.counted());
+        drop(bvmap.par_drain());
+        assert_eq!(drops.get(), 1001);
+    }
+
+    #[test]
+    fn debug_eq_and_hash() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        fn hash(bvmap: &BvMap<usize, &str>) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            bvmap.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let mut a: BvMap<usize, &str> = vec!["x", "y", "z"].into_iter().collect();
+        let mut b: BvMap<usize, &str> = vec!["x", "-", "z"].into_iter().collect();
+        assert_ne!(a, b);
+        a.remove(1);
+        a.remove(0);
+        a.insert("x");
+        b.remove(1);
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+        assert_eq!(format!("{:?}", a), r#"{0: "x", 2: "z"}"#);
+        b.remove(2);
+        assert_ne!(a, b);
+        assert_ne!(hash(&a), hash(&b));
+        b.insert("z");
+        assert_eq!(a, b);
+        assert_eq!(format!("{:?}", BvMap::<usize, u8>::new()), "{}");
+    }
+
+    #[test]
+    fn invariant_checks() {
+        use crate::{InvariantError, Slot};
+
+        let mut bvmap: BvMap<usize, usize> = (0..10).collect();
+        bvmap.remove(3);
+        bvmap.remove(7);
+        bvmap.remove(5);
+        assert_eq!(bvmap.validate(), Ok(()));
+        bvmap.debug_assert_invariants();
+
+        bvmap.vec[7] = Slot { next_free: 5 };
+        assert_eq!(bvmap.validate(), Err(InvariantError::FreeListCycle));
+        bvmap.vec[7] = Slot { next_free: 11 };
+        assert_eq!(
+            bvmap.validate(),
+            Err(InvariantError::FreeListOutOfRange { index: 11 })
+        );
+        bvmap.vec[7] = Slot { next_free: 4 };
+        assert_eq!(
+            bvmap.validate(),
+            Err(InvariantError::FreeListOccupied { index: 4 })
+        );
+        bvmap.vec[7] = Slot { next_free: 10 };
+        assert_eq!(
+            bvmap.validate(),
+            Err(InvariantError::FreeListIncomplete {
+                reachable: 2,
+                vacant: 3
+            })
+        );
+        bvmap.vec[7] = Slot { next_free: 3 };
+        bvmap.len = 6;
+        assert_eq!(
+            bvmap.validate(),
+            Err(InvariantError::LenMismatch {
+                len: 6,
+                occupied: 7
+            })
+        );
+        bvmap.len = 7;
+
+        let mut bvmap: BvMap<usize, usize> = BvMap::with_policy(AllocPolicy::LowestFirst);
+        bvmap.extend(0..10);
+        bvmap.remove(8);
+        bvmap.remove(2);
+        assert_eq!(bvmap.validate(), Ok(()));
+        bvmap.next_free = 8;
+        assert_eq!(
+            bvmap.validate(),
+            Err(InvariantError::NextFreeNotLowest {
+                next_free: 8,
+                lowest: 2
+            })
+        );
+        bvmap.next_free = 2;
+    }
+
+    #[test]
+    fn custom_key_types() {
+        new_key_type! {
+            struct NodeKey;
+            pub(crate) struct EdgeKey;
+        }
+
+        let mut nodes: BvMap<NodeKey, &str> = BvMap::new();
+        let mut edges: BvMap<EdgeKey, (NodeKey, NodeKey)> = BvMap::new();
+        let a = nodes.insert("a");
+        let b = nodes.insert("b");
+        let ab = edges.insert((a, b));
+        assert_eq!(a.index(), 0);
+        assert_eq!(NodeKey::from_index(1), b);
+        assert_eq!(ab.index(), 0);
+        assert_eq!(nodes[edges[ab].1], "b");
+        assert_eq!(nodes.keys().collect::<Vec<_>>(), vec![a, b]);
+
+        let mut weights: BvSecondaryMap<EdgeKey, f32> = BvSecondaryMap::new();
+        weights.insert(ab, 0.5);
+        assert_eq!(weights.get(ab), Some(&0.5));
+        assert_eq!(format!("{:?}", a), "NodeKey(0)");
+    }
+
+    #[test]
+    fn compact_keys() {
+        use std::mem::size_of;
+        use std::num::NonZeroU32;
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        new_key_type! {
+            struct SmallKey(u32);
+            struct NicheKey(NonZeroU32);
+        }
+
+        let mut small: BvMap<SmallKey, &str> = BvMap::new();
+        let a = small.insert("a");
+        assert_eq!(small[a], "a");
+        assert_eq!(size_of::<SmallKey>(), 4);
+        assert_eq!(SmallKey::try_from_index(u32::MAX as usize + 1), None);
+
+        let mut niche: BvMap<NicheKey, &str> = BvMap::new();
+        let b = niche.insert("b");
+        assert_eq!(b.index(), 0);
+        assert_eq!(niche[b], "b");
+        assert_eq!(size_of::<Option<NicheKey>>(), size_of::<NicheKey>());
+        assert_eq!(NonZeroU32::from_index(0).get(), 1);
+        assert_eq!(NonZeroU32::try_from_index(u32::MAX as usize), None);
+        assert_eq!(u16::try_from_index(65535), Some(65535));
+        assert_eq!(u16::try_from_index(65536), None);
+
+        // A key type with room for three indices.
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        struct TinyKey(u8);
+        impl Key for TinyKey {
+            const MAX_INDEX: usize = 2;
+            fn from_index(index: usize) -> TinyKey {
+                TinyKey(index as u8)
+            }
+            fn index(self) -> usize {
+                self.0 as usize
+            }
+        }
+        let mut tiny: BvMap<TinyKey, i32> = BvMap::new();
+        for i in 0..3 {
+            tiny.insert(i);
+        }
+        // Freed slots can still be reused once the key space is used up.
+        tiny.remove(TinyKey(1));
+        assert_eq!(tiny.insert(4), TinyKey(1));
+        let result = catch_unwind(AssertUnwindSafe(|| tiny.insert(5)));
+        assert!(result.is_err());
+        assert_eq!(tiny.len(), 3);
+        tiny.validate().unwrap();
+    }
+
+    #[test]
+    fn fallible_insertion() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let mut limited: BvMap<usize, i32> = BvMap::with_capacity_limit(2);
+        assert_eq!(limited.capacity_limit(), Some(2));
+        assert_eq!(limited.try_reserve(3), Err(InsertError::CapacityLimit));
+        assert_eq!(limited.try_insert(1), Ok(0));
+        assert_eq!(limited.try_insert(2), Ok(1));
+        assert_eq!(limited.try_insert(3), Err((InsertError::CapacityLimit, 3)));
+        assert_eq!(
+            limited.try_vacant_entry().err(),
+            Some(InsertError::CapacityLimit)
+        );
+        assert_eq!(
+            limited.try_insert_with_key(|_| unreachable!()),
+            Err(InsertError::CapacityLimit)
+        );
+        let result = catch_unwind(AssertUnwindSafe(|| limited.vacant_entry().key()));
+        assert!(result.is_err());
+        assert_eq!(limited.try_reserve(0), Ok(()));
+        limited.remove(0);
+        assert_eq!(limited.try_reserve(1), Ok(()));
+        assert_eq!(limited.try_insert(4), Ok(0));
+        assert_eq!(limited.len(), 2);
+
+        // A preallocated, capped map that hands out the lowest keys first.
+        let mut capped: BvMap<usize, i32> = BvMap::with_capacity(4);
+        capped.set_policy(AllocPolicy::LowestFirst);
+        capped.set_capacity_limit(Some(4));
+        capped.extend(0..4);
+        assert_eq!(capped.try_insert(4), Err((InsertError::CapacityLimit, 4)));
+        assert_eq!(capped.capacity(), 4);
+        capped.remove(2);
+        capped.remove(0);
+        assert_eq!(capped.insert(5), 0);
+        capped.remove(3);
+        capped.set_policy(AllocPolicy::Lifo);
+        capped.validate().unwrap();
+        assert_eq!(capped.insert(6), 2);
+        assert_eq!(capped.insert(7), 3);
+        capped.set_capacity_limit(None);
+        assert_eq!(capped.insert(8), 4);
+
+        let mut narrow: BvMap<u16, ()> = BvMap::new();
+        assert_eq!(narrow.try_reserve(65536), Ok(()));
+        assert_eq!(
+            narrow.try_reserve(65537),
+            Err(InsertError::KeySpaceExhausted)
+        );
+
+        let mut huge: BvMap<usize, u64> = BvMap::new();
+        match huge.try_reserve(usize::MAX) {
+            Err(InsertError::AllocFailed(_)) => {}
+            other => panic!("expected allocation failure, got {:?}", other),
+        }
+        assert!(huge.is_empty());
+        assert_eq!(
+            InsertError::CapacityLimit.to_string(),
+            "capacity limit reached"
+        );
+    }
+
+    #[test]
+    fn chunked_drop_survives_panicking_destructor() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let drops = Drops::default();
+        let mut map: ChunkedBvMap<usize, Counted> = ChunkedBvMap::new();
+        map.insert(drops.bomb());
+        for _ in 0..100 {
+            map.insert(drops.counted());
+        }
+        assert!(catch_unwind(AssertUnwindSafe(|| drop(map))).is_err());
+        assert_eq!(drops.get(), 101);
+    }
+
+    #[test]
+    fn chunked_values_stay_put() {
+        use std::marker::PhantomPinned;
+
+        struct Pinned {
+            id: usize,
+            _counted: Counted,
+            _pin: PhantomPinned,
+        }
+
+        let drops = Drops::default();
+        let mut map: ChunkedBvMap<usize, Pinned> = ChunkedBvMap::new();
+        let mut addresses = Vec::new();
+        for id in 0..200 {
+            let (k, v) = map.insert_pin(Pinned {
+                id,
+                _counted: drops.counted(),
+                _pin: PhantomPinned,
+            });
+            assert_eq!(k, id);
+            addresses.push(&*v as *const Pinned);
+        }
+        assert_eq!(map.len(), 200);
+        assert_eq!(map.capacity(), 256);
+        for (k, v) in &map {
+            assert_eq!(v.id, k);
+            assert_eq!(v as *const Pinned, addresses[k]);
+        }
+
+        assert!(map.delete(70));
+        assert!(!map.delete(70));
+        assert!(map.get(70).is_none());
+        assert_eq!(drops.get(), 1);
+        let (k, _) = map.insert_pin(Pinned {
+            id: 1000,
+            _counted: drops.counted(),
+            _pin: PhantomPinned,
+        });
+        assert_eq!(k, 70);
+        assert_eq!(map[70].id, 1000);
+        assert!(map.get_pin_mut(199).is_some());
+        assert!(map.get_pin_mut(200).is_none());
+        assert_eq!(map.keys().count(), 200);
+        drop(map);
+        assert_eq!(drops.get(), 201);
+
+        let mut unpinned: ChunkedBvMap<u32, String> = ChunkedBvMap::new();
+        let a = unpinned.insert("a".to_string());
+        let b = unpinned.insert("b".to_string());
+        unpinned.get_mut(a).unwrap().push('!');
+        assert_eq!(unpinned.remove(a).as_deref(), Some("a!"));
+        assert_eq!(unpinned.remove(a), None);
+        assert_eq!(unpinned.insert("c".to_string()), a);
+        assert!(unpinned.contains_key(b));
+        assert_eq!(unpinned.len(), 2);
+    }
+
+    #[test]
+    fn sharded_map_across_threads() {
+        use std::mem::replace;
+        use std::thread;
+
+        let map: ShardedBvMap<u32, usize> = ShardedBvMap::with_shards(3);
+        assert_eq!(map.shard_count(), 4);
+        let keys: Vec<Vec<u32>> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|t| {
+                    let map = &map;
+                    scope.spawn(move || {
+                        let keys: Vec<u32> = (0..500).map(|i| map.insert(t * 1000 + i)).collect();
+                        for &k in keys.iter().step_by(2) {
+                            assert!(map.remove(k).is_some());
+                        }
+                        keys
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+
+        assert_eq!(map.len(), 1000);
+        for (t, keys) in keys.iter().enumerate() {
+            for (i, &k) in keys.iter().enumerate() {
+                if i % 2 == 0 {
+                    // Another thread may have reused the slot since.
+                    assert_ne!(map.get(k), Some(t * 1000 + i));
+                } else {
+                    assert_eq!(map.get(k), Some(t * 1000 + i));
+                }
+            }
+        }
+        let k = keys[0][1];
+        assert!(map.contains_key(k));
+        assert_eq!(map.with_mut(k, |v| replace(v, 7)), Some(1));
+        assert_eq!(map.with(k, |v| *v), Some(7));
+        let mut all = Vec::new();
+        map.for_each(|k, &v| all.push((k, v)));
+        assert_eq!(all.len(), 1000);
+        for (k, v) in all {
+            assert_eq!(map.get(k), Some(v));
+        }
+    }
+
+    #[test]
+    fn atomic_map_across_threads() {
+        use std::thread;
+
+        let mut map: AtomicBvMap<u32, String> = AtomicBvMap::with_capacity(300);
+        let keys: Vec<Vec<u32>> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|t| {
+                    let map = &map;
+                    scope.spawn(move || {
+                        let mut kept = Vec::new();
+                        for i in 0..100 {
+                            let k = map.insert(format!("{}-{}", t, i));
+                            if i % 2 == 0 {
+                                assert_eq!(map.remove(k), Some(format!("{}-{}", t, i)));
+                            } else {
+                                kept.push(k);
+                            }
+                        }
+                        kept
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+
+        assert_eq!(map.len(), 200);
+        for (t, kept) in keys.iter().enumerate() {
+            for (i, &k) in kept.iter().enumerate() {
+                assert_eq!(map.get(k), Some(&format!("{}-{}", t, 2 * i + 1)));
+            }
+        }
+        map.get_mut(keys[0][0]).unwrap().push('!');
+        assert_eq!(map.remove(keys[0][0]).as_deref(), Some("0-1!"));
+        assert_eq!(map.remove(keys[0][0]), None);
+        assert!(!map.contains_key(1000));
+
+        let full: AtomicBvMap<usize, i32> = AtomicBvMap::with_capacity(2);
+        full.insert(1);
+        full.insert(2);
+        assert_eq!(full.try_insert(3), Err((InsertError::CapacityLimit, 3)));
+        assert_eq!(full.remove(0), Some(1));
+        assert_eq!(full.try_insert(4), Ok(0));
+    }
+
+    #[test]
+    fn dense_storage() {
+        let mut map: DenseBvMap<u32, &str> = DenseBvMap::new();
+        let a = map.insert("a");
+        let b = map.insert("b");
+        let c = map.insert("c");
+        assert_eq!(map.as_slice(), &["a", "b", "c"]);
+
+        // The last value fills the hole.
+        assert_eq!(map.remove(a), Some("a"));
+        assert_eq!(map.remove(a), None);
+        assert_eq!(map.as_slice(), &["c", "b"]);
+        assert_eq!(map.iter().collect::<Vec<_>>(), vec![(c, &"c"), (b, &"b")]);
+        assert_eq!(map[c], "c");
+        assert!(!map.contains_key(a));
+
+        assert_eq!(map.insert("d"), a);
+        map[b] = "B";
+        for (_, v) in &mut map {
+            *v = if *v == "d" { "D" } else { v };
+        }
+        map.as_mut_slice()[0] = "C";
+        assert_eq!(map.as_slice(), &["C", "B", "D"]);
+        assert_eq!(map.keys().count(), 3);
+        assert_eq!(map.values().len(), 3);
+
+        assert_eq!(map.remove(c), Some("C"));
+        assert_eq!(map.remove(b), Some("B"));
+        assert_eq!(map.get(a), Some(&"D"));
+        assert_eq!(map.clone().len(), 1);
+    }
+
+    #[test]
+    fn column_storage() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let drops = Drops::default();
+
+        let mut map: BvColumns<u32, (i32, String, Counted)> = BvColumns::new();
+        let a = map.insert((1, "a".to_string(), drops.counted()));
+        let b = map.insert((2, "b".to_string(), drops.counted()));
+        let c = map.insert((3, "c".to_string(), drops.counted()));
+        assert_eq!(map.len(), 3);
+
+        let (numbers, names, _) = map.columns();
+        assert_eq!(numbers.get(b), Some(&2));
+        assert_eq!(names[c], "c");
+        assert_eq!(numbers.values().sum::<i32>(), 6);
+
+        let (numbers, names, _) = map.columns_mut();
+        for (_, (n, name)) in (numbers, names).join() {
+            *n *= name.len() as i32 * 10;
+        }
+        let (numbers, mut names, counted) = map.columns_mut();
+        names[a].push('!');
+        assert_eq!(names.get_mut(b).map(|s| s.len()), Some(1));
+        let picked: Vec<_> = (numbers, counted)
+            .join()
+            .map(|(k, (n, _))| (k, *n))
+            .collect();
+        assert_eq!(picked, vec![(a, 10), (b, 20), (c, 30)]);
+        let mut other: BvColumns<u32, (i32,)> = BvColumns::new();
+        other.insert((0,));
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            (map.columns().0, other.columns().0).join().count()
+        }));
+        assert!(result.is_err());
+
+        let (n, name, _) = map.remove(b).unwrap();
+        assert_eq!((n, name.as_str()), (20, "b"));
+        assert_eq!(drops.get(), 1);
+        assert!(map.remove(b).is_none());
+        assert!(map.get(b).is_none());
+        assert_eq!(map.insert((4, "d".to_string(), drops.counted())), b);
+
+        for (_, (n, _, _)) in map.iter_mut() {
+            *n += 1;
+        }
+        let rows: Vec<_> = map
+            .iter()
+            .map(|(k, (n, s, _))| (k, *n, s.as_str()))
+            .collect();
+        assert_eq!(rows, vec![(a, 11, "a!"), (b, 5, "d"), (c, 31, "c")]);
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec![a, b, c]);
+        *map.get_mut(c).unwrap().0 = 0;
+        assert_eq!(map.get(c).map(|row| *row.0), Some(0));
+        drop(map);
+        assert_eq!(drops.get(), 4);
+    }
+
+    #[test]
+    fn sharded_map_fills_every_shard() {
+        let map: ShardedBvMap<u16, ()> = ShardedBvMap::with_shards(4);
+        for _ in 0..=u16::MAX as usize {
+            map.insert(());
+        }
+        assert_eq!(map.len(), 1 << 16);
+        assert_eq!(
+            map.try_insert(()),
+            Err((InsertError::KeySpaceExhausted, ()))
+        );
+        assert_eq!(map.remove(12345), Some(()));
+        assert_eq!(map.try_insert(()), Ok(12345));
+    }
+
+    #[test]
+    fn secondary_map() {
+        let mut entities: BvMap<usize, &str> = BvMap::new();
+        let mut health: BvSecondaryMap<usize, u32> = BvSecondaryMap::new();
+        let e1 = entities.insert("e1");
+        let e2 = entities.insert("e2");
+        let e3 = entities.insert("e3");
+        assert_eq!(health.insert(e3, 30), None);
+        assert_eq!(health.insert(e1, 10), None);
+        assert_eq!(health.insert(e1, 11), Some(10));
+        assert_eq!(health.len(), 2);
+        assert!(!health.contains_key(e2));
+        assert_eq!(health.get(e2), None);
+        assert_eq!(health.get(100), None);
+        *health.get_mut(e3).unwrap() += 1;
+        assert_eq!(
+            health.iter().collect::<Vec<_>>(),
+            vec![(e1, &11), (e3, &31)]
+        );
+        assert_eq!(health.remove(e1), Some(11));
+        assert_eq!(health.remove(e1), None);
+        assert_eq!(health.clone().iter_mut().count(), 1);
+    }
+
+    #[test]
+    fn stale_generational_key() {
+        let mut map: GenBvMap<GenKey, usize> = GenBvMap::new();
+        let a1 = map.insert(11);
+        let a2 = map.insert(12);
+        assert_eq!(map.remove(a1), Some(11));
+        let a3 = map.insert(13);
+        assert_eq!(a3.index(), a1.index());
+        assert_eq!(map.get(a1), None);
+        assert_eq!(map.remove(a1), None);
+        assert_eq!(map.get(a3), Some(&13));
+        *map.get_mut(a2).unwrap() += 100;
+        assert_eq!(map.iter().collect::<Vec<_>>(), vec![(a3, &13), (a2, &112)]);
+
+        new_key_type! {
+            struct BulletKey(GenKey);
+        }
+        let mut bullets: GenBvMap<BulletKey, &str> = GenBvMap::new();
+        let b1 = bullets.insert("b1");
+        bullets.remove(b1);
+        let b2 = bullets.insert("b2");
+        assert_ne!(b1, b2);
+        assert_eq!(bullets.get(b1), None);
+        assert_eq!(bullets.get(b2), Some(&"b2"));
+    }
 }
@@ -0,0 +1,180 @@
+use crate::bitset::{ones_in, BitSet, BITS};
+use crate::{BvMap, Key, Slot};
+use rayon::iter::plumbing::{bridge_unindexed, Folder, UnindexedConsumer, UnindexedProducer};
+use rayon::prelude::*;
+use std::marker::PhantomData;
+use std::mem::{needs_drop, swap, take, ManuallyDrop};
+use std::slice;
+
+// Lets workers reach disjoint slots of the same vec. Every index is handed to
+// exactly one worker, because each worker owns a distinct range of bitset words.
+struct SlotsPtr<V>(*mut Slot<V>);
+
+unsafe impl<V: Send> Send for SlotsPtr<V> {}
+unsafe impl<V: Send> Sync for SlotsPtr<V> {}
+
+impl<V> Clone for SlotsPtr<V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<V> Copy for SlotsPtr<V> {}
+
+impl<K: Key + Send, V> BvMap<K, V> {
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = (K, &V)> + '_
+    where
+        V: Sync,
+    {
+        let slots = &self.vec[..];
+        self.bitvec
+            .words()
+            .par_iter()
+            .enumerate()
+            .flat_map_iter(move |(word_index, word)| {
+                ones_in(slice::from_ref(word)).map(move |bit| {
+                    let index = word_index * BITS + bit;
+                    (K::from_index(index), unsafe { &*slots[index].value })
+                })
+            })
+    }
+
+    pub fn par_iter_mut(&mut self) -> impl ParallelIterator<Item = (K, &mut V)> + '_
+    where
+        V: Send,
+    {
+        let slots = SlotsPtr(self.vec.as_mut_ptr());
+        self.bitvec
+            .words()
+            .par_iter()
+            .enumerate()
+            .flat_map_iter(move |(word_index, word)| {
+                ones_in(slice::from_ref(word)).map(move |bit| {
+                    let index = word_index * BITS + bit;
+                    (K::from_index(index), unsafe {
+                        &mut *(*slots.0.add(index)).value
+                    })
+                })
+            })
+    }
+
+    /// Removes all values in parallel, yielding them with their keys. The map
+    /// is empty afterwards even if the iterator is never run, and keeps its
+    /// allocation.
+    pub fn par_drain(&mut self) -> ParDrain<'_, K, V>
+    where
+        V: Send,
+    {
+        ParDrain { map: self }
+    }
+}
+
+pub struct ParDrain<'a, K, V> {
+    map: &'a mut BvMap<K, V>,
+}
+
+// Holds the bits of a map while producers move its values out, and gives them
+// back cleared even if a worker panics. The values a panicking worker had left
+// are leaked.
+struct Restore<'a, K, V> {
+    map: &'a mut BvMap<K, V>,
+    bitvec: BitSet,
+}
+
+impl<'a, K, V> Drop for Restore<'a, K, V> {
+    fn drop(&mut self) {
+        self.bitvec.clear();
+        swap(&mut self.map.bitvec, &mut self.bitvec);
+    }
+}
+
+impl<'a, K: Key + Send, V: Send> ParallelIterator for ParDrain<'a, K, V> {
+    type Item = (K, V);
+
+    fn drive_unindexed<C: UnindexedConsumer<(K, V)>>(self, consumer: C) -> C::Result {
+        let bitvec = take(&mut self.map.bitvec);
+        let restore = Restore {
+            map: &mut *self.map,
+            bitvec,
+        };
+        let producer = DrainProducer {
+            words: restore.bitvec.words(),
+            first_word: 0,
+            slots: SlotsPtr(restore.map.vec.as_mut_ptr()),
+            marker: PhantomData,
+        };
+        let result = bridge_unindexed(producer, consumer);
+        drop(restore);
+        self.map.clear_slots();
+        self.map.check_invariants();
+        result
+    }
+}
+
+impl<'a, K, V> Drop for ParDrain<'a, K, V> {
+    fn drop(&mut self) {
+        self.map.clear_slots();
+    }
+}
+
+struct DrainProducer<'a, K, V> {
+    words: &'a [u64],
+    first_word: usize,
+    slots: SlotsPtr<V>,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<'a, K: Key + Send, V: Send> UnindexedProducer for DrainProducer<'a, K, V> {
+    type Item = (K, V);
+
+    fn split(mut self) -> (Self, Option<Self>) {
+        if self.words.len() < 2 {
+            return (self, None);
+        }
+        let mid = self.words.len() / 2;
+        let (left, right) = take(&mut self.words).split_at(mid);
+        let right = DrainProducer {
+            words: right,
+            first_word: self.first_word + left.len(),
+            slots: self.slots,
+            marker: PhantomData,
+        };
+        let left = DrainProducer {
+            words: left,
+            first_word: self.first_word,
+            slots: self.slots,
+            marker: PhantomData,
+        };
+        (left, Some(right))
+    }
+
+    fn fold_with<F: Folder<(K, V)>>(mut self, mut folder: F) -> F {
+        let base = self.first_word * BITS;
+        let mut ones = ones_in(take(&mut self.words));
+        for bit in &mut ones {
+            let index = base + bit;
+            let v = unsafe { ManuallyDrop::take(&mut (*self.slots.0.add(index)).value) };
+            folder = folder.consume((K::from_index(index), v));
+            if folder.full() {
+                break;
+            }
+        }
+        for bit in ones {
+            unsafe { ManuallyDrop::drop(&mut (*self.slots.0.add(base + bit)).value) };
+        }
+        folder
+    }
+}
+
+// Rayon drops producers without folding them once the consumer is full, so
+// the values they still cover are dropped here.
+impl<'a, K, V> Drop for DrainProducer<'a, K, V> {
+    fn drop(&mut self) {
+        if needs_drop::<V>() {
+            let base = self.first_word * BITS;
+            for bit in ones_in(self.words) {
+                unsafe { ManuallyDrop::drop(&mut (*self.slots.0.add(base + bit)).value) };
+            }
+        }
+    }
+}
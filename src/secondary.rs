@@ -0,0 +1,149 @@
+use crate::bitset::BitSet;
+use crate::{Iter, IterMut, Key, Slot};
+use std::marker::PhantomData;
+use std::mem::{needs_drop, replace, ManuallyDrop};
+
+/// Map from keys handed out by a `BvMap` to extra values, using the same
+/// layout as `BvMap` itself. Values are inserted at a given key and the map
+/// grows as needed to hold it.
+pub struct BvSecondaryMap<K, V> {
+    len: usize,
+    bitvec: BitSet,
+    vec: Vec<Slot<V>>,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<K: Key, V> BvSecondaryMap<K, V> {
+    pub fn new() -> BvSecondaryMap<K, V> {
+        BvSecondaryMap {
+            len: 0,
+            bitvec: BitSet::new(),
+            vec: Vec::new(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains_key(&self, k: K) -> bool {
+        self.bitvec.get(k.index()) == Some(true)
+    }
+
+    /// Inserts `v` at `k`, returning the value previously stored there.
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        let k = k.index();
+        if k >= self.vec.len() {
+            let additional = k + 1 - self.vec.len();
+            self.vec.reserve(additional);
+            self.bitvec.reserve(additional);
+            while self.vec.len() <= k {
+                self.vec.push(Slot { next_free: 0 });
+                self.bitvec.push(false);
+            }
+        }
+        let slot = replace(
+            &mut self.vec[k],
+            Slot {
+                value: ManuallyDrop::new(v),
+            },
+        );
+        if self.bitvec[k] {
+            Some(ManuallyDrop::into_inner(unsafe { slot.value }))
+        } else {
+            self.bitvec.set(k, true);
+            self.len += 1;
+            None
+        }
+    }
+
+    pub fn get(&self, k: K) -> Option<&V> {
+        let k = k.index();
+        if self.bitvec.get(k)? {
+            Some(unsafe { &*self.vec[k].value })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, k: K) -> Option<&mut V> {
+        let k = k.index();
+        if self.bitvec.get(k)? {
+            Some(unsafe { &mut *self.vec[k].value })
+        } else {
+            None
+        }
+    }
+
+    pub fn remove(&mut self, k: K) -> Option<V> {
+        let k = k.index();
+        if self.bitvec.get(k)? {
+            self.bitvec.set(k, false);
+            self.len -= 1;
+            let slot = replace(&mut self.vec[k], Slot { next_free: 0 });
+            Some(ManuallyDrop::into_inner(unsafe { slot.value }))
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            ones: self.bitvec.ones(),
+            slots: self.vec.iter(),
+            next_index: 0,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut {
+            ones: self.bitvec.ones(),
+            slots: self.vec.iter_mut(),
+            next_index: 0,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<K: Key, V> Default for BvSecondaryMap<K, V> {
+    fn default() -> Self {
+        BvSecondaryMap::new()
+    }
+}
+
+impl<K, V: Clone> Clone for BvSecondaryMap<K, V> {
+    fn clone(&self) -> Self {
+        let mut vec: Vec<Slot<V>> = Vec::with_capacity(self.vec.len());
+        for (index, slot) in self.vec.iter().enumerate() {
+            vec.push(if self.bitvec[index] {
+                Slot {
+                    value: unsafe { &slot.value }.clone(),
+                }
+            } else {
+                Slot { next_free: 0 }
+            });
+        }
+        BvSecondaryMap {
+            len: self.len,
+            bitvec: self.bitvec.clone(),
+            vec,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V> Drop for BvSecondaryMap<K, V> {
+    fn drop(&mut self) {
+        if needs_drop::<V>() {
+            for index in self.bitvec.ones() {
+                unsafe { ManuallyDrop::drop(&mut self.vec[index].value) };
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::bitset::BitSet;
+use crate::{AllocPolicy, BvMap, Key, Slot};
+use serde::de::{Deserialize, Deserializer, Error};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use serde::{Deserialize as DeriveDeserialize, Serialize as DeriveSerialize};
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+
+// Vacant slots are written out with their free list link, so a deserialized
+// map hands out keys in the same order as the original would have.
+#[derive(DeriveSerialize)]
+enum SlotRef<'a, V> {
+    Occupied(&'a V),
+    Vacant(usize),
+}
+
+#[derive(DeriveDeserialize)]
+enum SlotRepr<V> {
+    Occupied(V),
+    Vacant(usize),
+}
+
+struct SlotsRef<'a, K, V>(&'a BvMap<K, V>);
+
+impl<'a, K, V: Serialize> Serialize for SlotsRef<'a, K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let map = self.0;
+        serializer.collect_seq(map.vec.iter().enumerate().map(|(index, slot)| {
+            if map.bitvec[index] {
+                SlotRef::Occupied(unsafe { &*slot.value })
+            } else {
+                SlotRef::Vacant(unsafe { slot.next_free })
+            }
+        }))
+    }
+}
+
+#[derive(DeriveDeserialize)]
+#[serde(rename = "BvMap")]
+struct BvMapRepr<V> {
+    next_free: usize,
+    #[serde(default)]
+    policy: AllocPolicy,
+    #[serde(default)]
+    limit: Option<usize>,
+    slots: Vec<SlotRepr<V>>,
+}
+
+impl<K, V: Serialize> Serialize for BvMap<K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("BvMap", 4)?;
+        state.serialize_field("next_free", &self.next_free)?;
+        state.serialize_field("policy", &self.policy)?;
+        state.serialize_field("limit", &self.limit)?;
+        state.serialize_field("slots", &SlotsRef(self))?;
+        state.end()
+    }
+}
+
+impl<'de, K: Key, V: Deserialize<'de>> Deserialize<'de> for BvMap<K, V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr: BvMapRepr<V> = BvMapRepr::deserialize(deserializer)?;
+        let mut map = BvMap {
+            next_free: repr.next_free,
+            len: 0,
+            policy: repr.policy,
+            limit: repr.limit,
+            bitvec: BitSet::with_capacity(repr.slots.len()),
+            vec: Vec::with_capacity(repr.slots.len()),
+            marker: PhantomData,
+        };
+        for slot in repr.slots {
+            match slot {
+                SlotRepr::Occupied(v) => {
+                    map.vec.push(Slot {
+                        value: ManuallyDrop::new(v),
+                    });
+                    map.bitvec.push(true);
+                    map.len += 1;
+                }
+                SlotRepr::Vacant(next_free) => {
+                    map.vec.push(Slot { next_free });
+                    map.bitvec.push(false);
+                }
+            }
+        }
+
+        // Inserting trusts the free list, so it has to be checked here.
+        map.validate().map_err(D::Error::custom)?;
+        Ok(map)
+    }
+}
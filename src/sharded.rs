@@ -0,0 +1,188 @@
+use crate::{BvMap, InsertError, Key};
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError, TryLockError};
+use std::thread;
+
+static THREADS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Numbers threads in the order they first insert, so that each starts at a
+    // different shard and keeps coming back to it.
+    static THREAD_INDEX: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+fn thread_index() -> usize {
+    THREAD_INDEX.with(|index| {
+        index.get().unwrap_or_else(|| {
+            let new = THREADS.fetch_add(1, Ordering::Relaxed);
+            index.set(Some(new));
+            new
+        })
+    })
+}
+
+/// A map that can be shared between threads, split into shards that each have
+/// their own `BvMap` behind a lock. Threads inserting at the same time are
+/// spread over the shards, so they rarely wait for each other.
+///
+/// The low bits of a key's index name its shard and the rest is the index
+/// within the shard, so lookups lock only the shard holding the value. Values
+/// are reached through closures, because a reference into a shard cannot
+/// outlive its lock.
+pub struct ShardedBvMap<K, V> {
+    shards: Box<[Mutex<BvMap<usize, V>>]>,
+    shard_bits: u32,
+    marker: PhantomData<fn(K) -> K>,
+}
+
+impl<K: Key, V> ShardedBvMap<K, V> {
+    /// Creates a map with one shard per available CPU, rounded up to a power
+    /// of two.
+    pub fn new() -> ShardedBvMap<K, V> {
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        ShardedBvMap::with_shards(cpus)
+    }
+
+    /// Creates a map with `shards` shards, rounded up to a power of two.
+    pub fn with_shards(shards: usize) -> ShardedBvMap<K, V> {
+        let shards = shards.max(1).next_power_of_two();
+        let shard_bits = shards.trailing_zeros();
+        // Keeps every local index small enough that it still fits in a key
+        // once the highest shard number is added.
+        let limit = K::MAX_INDEX
+            .checked_sub(shards - 1)
+            .map_or(0, |max| (max >> shard_bits).saturating_add(1));
+        ShardedBvMap {
+            shards: (0..shards)
+                .map(|_| Mutex::new(BvMap::with_capacity_limit(limit)))
+                .collect(),
+            shard_bits,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Counts the values of all shards, locking each in turn. Other threads may
+    /// change the count while this runs.
+    pub fn len(&self) -> usize {
+        (0..self.shards.len()).map(|s| self.lock(s).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Callers only panic while holding a lock inside user closures, which
+    // cannot break the shard's invariants, so a poisoned lock is still usable.
+    fn lock(&self, shard: usize) -> MutexGuard<'_, BvMap<usize, V>> {
+        self.shards[shard]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn split(&self, k: K) -> (usize, usize) {
+        let index = k.index();
+        (index & (self.shards.len() - 1), index >> self.shard_bits)
+    }
+
+    fn join(&self, shard: usize, local: usize) -> K {
+        K::from_index(local << self.shard_bits | shard)
+    }
+
+    /// Like `try_insert`, but panics once every shard is full.
+    pub fn insert(&self, v: V) -> K {
+        match self.try_insert(v) {
+            Ok(key) => key,
+            Err((e, _)) => panic!("ShardedBvMap insert failed: {}", e),
+        }
+    }
+
+    /// Inserts `v` into the first shard that is not locked, starting from the
+    /// calling thread's own shard. Waits for a shard only if all are busy.
+    /// Shards that are full are skipped, so the key space is exhausted only
+    /// once every shard is full.
+    pub fn try_insert(&self, v: V) -> Result<K, (InsertError, V)> {
+        let shards = self.shards.len();
+        let start = thread_index() & (shards - 1);
+        // The first pass skips locked shards and the second waits for them.
+        for wait in [false, true] {
+            for i in 0..shards {
+                let shard = (start + i) & (shards - 1);
+                let mut map = if wait {
+                    self.lock(shard)
+                } else {
+                    match self.shards[shard].try_lock() {
+                        Ok(map) => map,
+                        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                        Err(TryLockError::WouldBlock) => continue,
+                    }
+                };
+                // Checking for room first keeps `v` when the shard is full.
+                match map.try_reserve(1) {
+                    Ok(()) => return Ok(self.join(shard, map.insert(v))),
+                    Err(InsertError::CapacityLimit) => {}
+                    Err(e) => return Err((e, v)),
+                }
+            }
+        }
+        Err((InsertError::KeySpaceExhausted, v))
+    }
+
+    pub fn remove(&self, k: K) -> Option<V> {
+        let (shard, local) = self.split(k);
+        self.lock(shard).remove(local)
+    }
+
+    pub fn contains_key(&self, k: K) -> bool {
+        let (shard, local) = self.split(k);
+        self.lock(shard).get(local).is_some()
+    }
+
+    pub fn get(&self, k: K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.with(k, V::clone)
+    }
+
+    /// Calls `f` with the value at `k` while its shard is locked.
+    pub fn with<R, F: FnOnce(&V) -> R>(&self, k: K, f: F) -> Option<R> {
+        let (shard, local) = self.split(k);
+        self.lock(shard).get(local).map(f)
+    }
+
+    /// Calls `f` with the value at `k` while its shard is locked.
+    pub fn with_mut<R, F: FnOnce(&mut V) -> R>(&self, k: K, f: F) -> Option<R> {
+        let (shard, local) = self.split(k);
+        self.lock(shard).get_mut(local).map(f)
+    }
+
+    /// Calls `f` for every value, one shard at a time. Only the shard being
+    /// visited is locked.
+    pub fn for_each<F: FnMut(K, &V)>(&self, mut f: F) {
+        for shard in 0..self.shards.len() {
+            for (local, v) in self.lock(shard).iter() {
+                f(self.join(shard, local), v);
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self, k: K) -> Option<&mut V> {
+        let (shard, local) = self.split(k);
+        self.shards[shard]
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(local)
+    }
+}
+
+impl<K: Key, V> Default for ShardedBvMap<K, V> {
+    fn default() -> Self {
+        ShardedBvMap::new()
+    }
+}
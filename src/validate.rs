@@ -0,0 +1,138 @@
+use crate::{AllocPolicy, BvMap, Key};
+use std::error::Error;
+use std::fmt;
+
+/// A broken internal invariant, as reported by `BvMap::validate`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvariantError {
+    /// The occupancy bits do not cover exactly the slots.
+    BitsMismatch { bits: usize, slots: usize },
+    /// The stored length differs from the number of occupied slots.
+    LenMismatch { len: usize, occupied: usize },
+    /// A free list link points past the end of the slots.
+    FreeListOutOfRange { index: usize },
+    /// A free list link points at an occupied slot.
+    FreeListOccupied { index: usize },
+    /// The free list loops back on itself.
+    FreeListCycle,
+    /// Some vacant slots cannot be reached from the free list.
+    FreeListIncomplete { reachable: usize, vacant: usize },
+    /// There are more slots than the key type has indices for.
+    TooManySlots { slots: usize, max_index: usize },
+    /// With `AllocPolicy::LowestFirst`, the next slot to fill is not the lowest
+    /// vacant one.
+    NextFreeNotLowest { next_free: usize, lowest: usize },
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            InvariantError::BitsMismatch { bits, slots } => {
+                write!(f, "{} occupancy bits for {} slots", bits, slots)
+            }
+            InvariantError::LenMismatch { len, occupied } => {
+                write!(f, "length is {} but {} slots are occupied", len, occupied)
+            }
+            InvariantError::FreeListOutOfRange { index } => {
+                write!(f, "free list links to out of range slot {}", index)
+            }
+            InvariantError::FreeListOccupied { index } => {
+                write!(f, "free list links to occupied slot {}", index)
+            }
+            InvariantError::FreeListCycle => write!(f, "free list contains a cycle"),
+            InvariantError::FreeListIncomplete { reachable, vacant } => write!(
+                f,
+                "free list reaches {} of {} vacant slots",
+                reachable, vacant
+            ),
+            InvariantError::TooManySlots { slots, max_index } => write!(
+                f,
+                "{} slots but the highest key index is {}",
+                slots, max_index
+            ),
+            InvariantError::NextFreeNotLowest { next_free, lowest } => write!(
+                f,
+                "next free slot is {} but lowest vacant slot is {}",
+                next_free, lowest
+            ),
+        }
+    }
+}
+
+impl Error for InvariantError {}
+
+impl<K: Key, V> BvMap<K, V> {
+    /// Checks that the occupancy bits, the length and the free list agree with
+    /// each other. Every unchecked slot access relies on this.
+    pub fn validate(&self) -> Result<(), InvariantError> {
+        let slots = self.vec.len();
+        if self.bitvec.len() != slots {
+            return Err(InvariantError::BitsMismatch {
+                bits: self.bitvec.len(),
+                slots,
+            });
+        }
+        if slots > 0 && slots - 1 > K::MAX_INDEX {
+            return Err(InvariantError::TooManySlots {
+                slots,
+                max_index: K::MAX_INDEX,
+            });
+        }
+        let occupied = self.bitvec.ones().count();
+        if occupied != self.len {
+            return Err(InvariantError::LenMismatch {
+                len: self.len,
+                occupied,
+            });
+        }
+
+        let vacant = slots - occupied;
+        match self.policy {
+            AllocPolicy::Lifo => {
+                let mut free = self.next_free;
+                let mut reachable = 0;
+                while free != slots {
+                    match self.bitvec.get(free) {
+                        None => return Err(InvariantError::FreeListOutOfRange { index: free }),
+                        Some(true) => return Err(InvariantError::FreeListOccupied { index: free }),
+                        Some(false) => {}
+                    }
+                    reachable += 1;
+                    if reachable > vacant {
+                        return Err(InvariantError::FreeListCycle);
+                    }
+                    free = unsafe { self.vec[free].next_free };
+                }
+                if reachable != vacant {
+                    return Err(InvariantError::FreeListIncomplete { reachable, vacant });
+                }
+            }
+            AllocPolicy::LowestFirst => {
+                let lowest = self.bitvec.first_zero_from(0).unwrap_or(slots);
+                if self.next_free != lowest {
+                    return Err(InvariantError::NextFreeNotLowest {
+                        next_free: self.next_free,
+                        lowest,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Panics if `validate` fails. Does nothing in release builds.
+    pub fn debug_assert_invariants(&self) {
+        if cfg!(debug_assertions) {
+            if let Err(e) = self.validate() {
+                panic!("BvMap invariant violated: {}", e);
+            }
+        }
+    }
+
+    // Runs after every mutation when the "debug-checks" feature is enabled.
+    #[inline]
+    pub(crate) fn check_invariants(&self) {
+        #[cfg(feature = "debug-checks")]
+        self.debug_assert_invariants();
+    }
+}
@@ -0,0 +1,92 @@
+// Explores the interleavings of AtomicBvMap operations. Run with
+// RUSTFLAGS="--cfg loom" cargo test --release --test loom
+#![cfg(loom)]
+
+use bvmap::AtomicBvMap;
+use loom::sync::Arc;
+use loom::thread;
+
+#[test]
+fn concurrent_inserts_take_distinct_slots() {
+    loom::model(|| {
+        let map: Arc<AtomicBvMap<usize, usize>> = Arc::new(AtomicBvMap::with_capacity(2));
+        let other = map.clone();
+        let worker = thread::spawn(move || other.insert(1));
+        let a = map.insert(2);
+        let b = worker.join().unwrap();
+        assert_ne!(a, b);
+        assert_eq!(map.remove(a), Some(2));
+        assert_eq!(map.remove(b), Some(1));
+        assert!(map.is_empty());
+    });
+}
+
+#[test]
+fn remove_sees_the_whole_value() {
+    loom::model(|| {
+        let map: Arc<AtomicBvMap<usize, String>> = Arc::new(AtomicBvMap::with_capacity(1));
+        let other = map.clone();
+        let worker = thread::spawn(move || other.insert("value".to_string()));
+        // With capacity 1 the worker's key is known up front. The remove
+        // either misses the value or reads all of it.
+        if let Some(v) = map.remove(0) {
+            assert_eq!(v, "value");
+        }
+        worker.join().unwrap();
+    });
+}
+
+#[test]
+fn only_one_remove_wins() {
+    loom::model(|| {
+        let map: Arc<AtomicBvMap<usize, String>> = Arc::new(AtomicBvMap::with_capacity(1));
+        let k = map.insert("value".to_string());
+        let other = map.clone();
+        let worker = thread::spawn(move || other.remove(k));
+        let here = map.remove(k);
+        let there = worker.join().unwrap();
+        assert!(here.is_some() != there.is_some());
+        assert_eq!(map.len(), 0);
+    });
+}
+
+#[test]
+fn freed_slot_is_reused_safely() {
+    loom::model(|| {
+        let map: Arc<AtomicBvMap<usize, String>> = Arc::new(AtomicBvMap::with_capacity(1));
+        let k = map.insert("old".to_string());
+        let other = map.clone();
+        // The map is full until the remove pushes the slot back, so an insert
+        // that succeeds writes into the slot the remove has just read from.
+        let worker = thread::spawn(move || other.remove(k));
+        let new = map.try_insert("new".to_string());
+        assert_eq!(worker.join().unwrap().as_deref(), Some("old"));
+        if let Ok(new) = new {
+            assert_eq!(new, k);
+            assert_eq!(map.remove(new).as_deref(), Some("new"));
+        }
+    });
+}
+
+#[test]
+fn free_list_survives_pop_and_push_races() {
+    loom::model(|| {
+        let map: Arc<AtomicBvMap<usize, usize>> = Arc::new(AtomicBvMap::with_capacity(2));
+        let a = map.insert(0);
+        let b = map.insert(1);
+        map.remove(a);
+        map.remove(b);
+        let other = map.clone();
+        let worker = thread::spawn(move || {
+            let k = other.insert(2);
+            other.remove(k);
+            other.insert(3)
+        });
+        let here = map.insert(4);
+        let there = worker.join().unwrap();
+        assert_ne!(here, there);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.remove(here), Some(4));
+        assert_eq!(map.remove(there), Some(3));
+    });
+}
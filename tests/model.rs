@@ -0,0 +1,42 @@
+mod ops;
+
+use ops::Op;
+use proptest::prelude::*;
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        3 => any::<u32>().prop_map(Op::Insert),
+        2 => any::<usize>().prop_map(Op::Remove),
+        2 => any::<usize>().prop_map(Op::Get),
+        1 => Just(Op::Clone),
+        1 => Just(Op::Iter),
+    ]
+}
+
+// Miri is far slower than native runs, so it gets fewer and shorter cases.
+// Run with `MIRIFLAGS=-Zmiri-disable-isolation cargo +nightly miri test --test model`.
+fn config() -> ProptestConfig {
+    if cfg!(miri) {
+        ProptestConfig {
+            cases: 4,
+            failure_persistence: None,
+            ..ProptestConfig::default()
+        }
+    } else {
+        ProptestConfig::default()
+    }
+}
+
+proptest! {
+    #![proptest_config(config())]
+
+    #[test]
+    fn matches_hash_map(ops in prop::collection::vec(op(), 0..if cfg!(miri) { 40 } else { 400 })) {
+        ops::run(&ops);
+    }
+}
+
+#[test]
+fn decoded_bytes() {
+    ops::run(&Op::decode(&[0, 1, 0, 0, 2, 0, 1, 0, 0, 3, 0, 0, 2, 0, 0, 0, 9, 0, 4, 0, 0]));
+}
@@ -0,0 +1,81 @@
+// Operations on a `BvMap` checked against a `HashMap` model. Shared by the
+// proptest suite in `tests/model.rs` and the fuzz target in `fuzz/`.
+#![allow(dead_code)]
+
+use bvmap::BvMap;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub enum Op {
+    Insert(u32),
+    // Removes and gets pick a key from the ones handed out so far, by index
+    // modulo their count, so that stale keys get exercised too.
+    Remove(usize),
+    Get(usize),
+    Clone,
+    Iter,
+}
+
+impl Op {
+    /// Turns arbitrary bytes into operations, three bytes per operation.
+    pub fn decode(data: &[u8]) -> Vec<Op> {
+        data.chunks_exact(3)
+            .map(|chunk| {
+                let arg = u16::from_le_bytes([chunk[1], chunk[2]]);
+                match chunk[0] % 5 {
+                    0 => Op::Insert(u32::from(arg)),
+                    1 => Op::Remove(usize::from(arg)),
+                    2 => Op::Get(usize::from(arg)),
+                    3 => Op::Clone,
+                    _ => Op::Iter,
+                }
+            })
+            .collect()
+    }
+}
+
+fn pick(keys: &[usize], i: usize) -> usize {
+    if keys.is_empty() {
+        i
+    } else {
+        keys[i % keys.len()]
+    }
+}
+
+pub fn run(ops: &[Op]) {
+    let mut map: BvMap<usize, String> = BvMap::new();
+    let mut model: HashMap<usize, String> = HashMap::new();
+    let mut keys = Vec::new();
+
+    for op in ops {
+        match *op {
+            Op::Insert(v) => {
+                let k = map.insert(v.to_string());
+                assert!(model.insert(k, v.to_string()).is_none(), "key {} reused", k);
+                keys.push(k);
+            }
+            Op::Remove(i) => {
+                let k = pick(&keys, i);
+                assert_eq!(map.remove(k), model.remove(&k));
+            }
+            Op::Get(i) => {
+                let k = pick(&keys, i);
+                assert_eq!(map.get(k), model.get(&k));
+            }
+            Op::Clone => {
+                let copy = map.clone();
+                assert!(copy == map);
+                map = copy;
+            }
+            Op::Iter => {
+                let mut entries = map.iter().map(|(k, v)| (k, v.clone())).collect::<Vec<_>>();
+                let mut expected = model.iter().map(|(k, v)| (*k, v.clone())).collect::<Vec<_>>();
+                entries.sort();
+                expected.sort();
+                assert_eq!(entries, expected);
+            }
+        }
+        assert_eq!(map.len(), model.len());
+        assert_eq!(map.validate(), Ok(()));
+    }
+}